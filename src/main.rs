@@ -1,6 +1,7 @@
+#![allow(clippy::upper_case_acronyms)]
+
 mod raymod;
 use raymod::*;
-use std::sync::Arc;
 
 use rayon::prelude::*;
 
@@ -14,46 +15,63 @@ fn main() {
     println!("sampling(use subpixel)={:?}",args.s*4);
 
     let samps: usize = args.s;
-    let MAX_DEPTH: i64 = 32;
+    let integrator = PathTracer::new(args.depth, args.rr_depth).with_spectral(args.spectral);
 
-    let mut scene: Box<dyn Scene>;
+    let scene: Box<dyn Scene>;
+    let bvh = BVHBuilder::new(args.bvh, args.bvh_stats);
     
-    let mut w: usize = args.w;
-    let mut h: usize = ((w as f64) / WIDE_ASPECT) as usize;
+    let w: usize = args.w;
+    let h: usize;
 
-   match args.m {
-        0 => {//デフォルトはゼロ
-            //---cornellbox
-            h=((w as f64)/SQUARE_ASPECT) as usize;
-            scene =Box::new( CornellDielectricScene::new() );
-        }
-        1 => {
-            //----Random_scene
-            h=((w as f64)/WIDE_ASPECT) as usize;
-            scene = Box::new( RandomScene::new() );
-	    }
-        2 => {
-            //---cornellbox
-            h=((w as f64)/SQUARE_ASPECT) as usize;
-            scene =Box::new( CornellBoxScene::new() );
-        }
-        3 => {
-            //---cornellbox
-            h=((w as f64)/SQUARE_ASPECT) as usize;
-            scene =Box::new( CornellDielectricScene::new() );
+    if let Some(path) = &args.scene {
+        match FileScene::load(path, &bvh, args.lights) {
+            Ok(file_scene) => {
+                h = ((w as f64) / file_scene.aspect) as usize;
+                scene = Box::new(file_scene);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
         }
-        _ => {
-            //---cornellbox
-            h=((w as f64)/SQUARE_ASPECT) as usize;
-            scene =Box::new( CornellBoxScene::new() );
+    } else {
+        match args.m {
+            0 => {//デフォルトはゼロ
+                //---cornellbox
+                h=((w as f64)/SQUARE_ASPECT) as usize;
+                scene =Box::new( CornellDielectricScene::new(&bvh, args.lights) );
+            }
+            1 => {
+                //----Random_scene
+                h=((w as f64)/WIDE_ASPECT) as usize;
+                scene = Box::new( RandomScene::new(&bvh) );
+            }
+            2 => {
+                //---cornellbox
+                h=((w as f64)/SQUARE_ASPECT) as usize;
+                scene =Box::new( CornellBoxScene::new(&bvh, args.lights) );
+            }
+            3 => {
+                //---cornellbox
+                h=((w as f64)/SQUARE_ASPECT) as usize;
+                scene =Box::new( CornellDielectricScene::new(&bvh, args.lights) );
+            }
+            _ => {
+                //---cornellbox
+                h=((w as f64)/SQUARE_ASPECT) as usize;
+                scene =Box::new( CornellBoxScene::new(&bvh, args.lights) );
+            }
         }
     }
+    for stats in bvh.stats() {
+        println!("{:?} {}", bvh.split, stats);
+    }
 
 
-    let mut image = vec![Color::zero(); (w * h) as usize];
-    let bands: Vec<(usize, &mut [Color])> = image.chunks_mut(w as usize).enumerate().collect();
+    let mut image = vec![Color::zero(); w * h];
+    let bands: Vec<(usize, &mut [Color])> = image.chunks_mut(w).enumerate().collect();
     bands.into_par_iter().for_each(|(y, band)| {
-        for x in 0..w {
+        for (x, pixel) in band.iter_mut().enumerate() {
             let mut r = Vec3::new(0.0, 0.0, 0.0);
             for _spp in 0..samps {
                 for _sy in 0..2 {
@@ -61,13 +79,13 @@ fn main() {
                         let u = (x as f64 + (_sx as f64 + random()) / 4.0) / (w as f64);
                         let v = (y as f64 + (_sy as f64 + random()) / 4.0) / (h as f64);
                         let ray = scene.get_ray(u, v);
-                        r = r + scene.ray_color(&ray,MAX_DEPTH,)
+                        r = r + integrator.radiance(scene.as_ref(), &ray)
                             / (samps as f64)
                             / 4.0;
                     }
                 }
             }
-            band[x as usize] = r;
+            *pixel = r;
         }
         if (y % 20) == 0 {
             print!("y={0}  :", y);
@@ -1,7 +1,10 @@
 use crate::raymod::*;
 
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::f64;
+use std::fmt;
+use std::sync::Arc;
 
 
 pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
@@ -44,96 +47,502 @@ impl AABB {
         }
         true
     }
+
+    /// Slab test with a precomputed inverse ray direction
+    pub fn hit_inv(&self, o: Vec3, inv_d: Vec3, mut t_min: f64, mut t_max: f64) -> bool {
+        for a in 0..3 {
+            let t0 = (self.min[a] - o[a]) * inv_d[a];
+            let t1 = (self.max[a] - o[a]) * inv_d[a];
+            let (t0, t1) = if inv_d[a] < 0.0 { (t1, t0) } else { (t0, t1) };
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max <= t_min {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
 }
 
-enum BVHNode {
-    Branch { left: Box<BVH>, right: Box<BVH> },
-    Leaf(Box<dyn Shape>),
+// BVHの分割方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BVHSplit {
+    Random,
+    Sah,
 }
 
-pub struct BVH {
-    tree: BVHNode,
+const SAH_BINS: usize = 16;
+const SAH_TRAVERSAL_COST: f64 = 1.0;
+const SAH_INTERSECT_COST: f64 = 1.0;
+const MAX_LEAF_SIZE: usize = 8;
+//走査スタックの大きさ。これより深くはしない
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BVHStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub primitives: usize,
+    pub depth: usize,
+    pub sah_cost: f64,
+}
+
+impl fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "bvh: nodes={} leaves={} primitives={} depth={} sah_cost={:.3}",
+            self.nodes, self.leaves, self.primitives, self.depth, self.sah_cost
+        )
+    }
+}
+
+/// Builds every BVH of a scene with one split method and, if asked,
+/// keeps the statistics of each build
+pub struct BVHBuilder {
+    pub split: BVHSplit,
+    stats: Option<RefCell<Vec<BVHStats>>>,
+}
+
+impl BVHBuilder {
+    pub fn new(split: BVHSplit, collect_stats: bool) -> Self {
+        Self { split, stats: if collect_stats { Some(RefCell::new(Vec::new())) } else { None } }
+    }
+
+    pub fn build(&self, shapes: Vec<Box<dyn Shape>>) -> BVH {
+        let bvh = BVH::new(shapes, self.split);
+        if let Some(stats) = &self.stats {
+            stats.borrow_mut().push(bvh.stats());
+        }
+        bvh
+    }
+
+    /// Statistics of the BVHs built so far, in build order
+    pub fn stats(&self) -> Vec<BVHStats> {
+        self.stats.as_ref().map(|s| s.borrow().clone()).unwrap_or_default()
+    }
+}
+
+// 構築時だけ使う木構造
+enum BuildNode {
+    Branch { left: Box<BuildNode>, right: Box<BuildNode>, axis: usize, bbox: AABB },
+    Leaf { shapes: Vec<Box<dyn Shape>>, bbox: AABB },
+}
+
+impl BuildNode {
+    fn bbox(&self) -> AABB {
+        match self {
+            BuildNode::Branch { bbox, .. } | BuildNode::Leaf { bbox, .. } => *bbox,
+        }
+    }
+}
+
+// 深さ優先で並べたノード。左の子は直後、右の子はoffsetの位置
+#[derive(Clone, Copy)]
+struct LinearNode {
     bbox: AABB,
+    // 葉なら最初のプリミティブ、内部ノードなら右の子の位置
+    offset: usize,
+    // 葉のプリミティブ数、内部ノードは0
+    count: usize,
+    axis: usize,
 }
 
+// プリミティブは光源リストと共有できるようにArcで持つ
+pub struct BVH {
+    nodes: Vec<LinearNode>,
+    shapes: Vec<Arc<dyn Shape>>,
+    //面積の累積。全体を一つの光源としてサンプリングする時に使う
+    area_cdf: Vec<f64>,
+}
+
+type Primitive = (Box<dyn Shape>, AABB);
+
 impl BVH {
-    pub fn new(mut shape: Vec<Box<dyn Shape>>) -> Self {
-        fn box_compare(axis: usize) -> impl FnMut(&Box<dyn Shape>, &Box<dyn Shape>) -> Ordering {
+    pub fn new(shape: Vec<Box<dyn Shape>>, split: BVHSplit) -> Self {
+        if shape.is_empty() {
+            panic!["no elements in scene"]
+        }
+        let prims: Vec<Primitive> = shape
+            .into_iter()
+            .map(|s| match s.bounding_box() {
+                Some(bbox) => (s, bbox),
+                None => panic!["no bounding box in bvh node"],
+            })
+            .collect();
+        let len = prims.len();
+        let root = match split {
+            BVHSplit::Random => Self::build_random(prims, 1),
+            BVHSplit::Sah => Self::build_sah(prims, 1),
+        };
+        let mut bvh = BVH { nodes: Vec::new(), shapes: Vec::with_capacity(len), area_cdf: Vec::with_capacity(len) };
+        bvh.flatten(root);
+        let mut total = 0.0;
+        for shape in &bvh.shapes {
+            total += shape.area();
+            bvh.area_cdf.push(total);
+        }
+        bvh
+    }
+
+    fn flatten(&mut self, node: BuildNode) {
+        match node {
+            BuildNode::Leaf { shapes, bbox } => {
+                self.nodes.push(LinearNode { bbox, offset: self.shapes.len(), count: shapes.len(), axis: 0 });
+                self.shapes.extend(shapes.into_iter().map(Arc::from));
+            }
+            BuildNode::Branch { left, right, axis, bbox } => {
+                let index = self.nodes.len();
+                self.nodes.push(LinearNode { bbox, offset: 0, count: 0, axis });
+                self.flatten(*left);
+                self.nodes[index].offset = self.nodes.len();
+                self.flatten(*right);
+            }
+        }
+    }
+
+    fn leaf(prims: Vec<Primitive>) -> BuildNode {
+        let bbox = prims.iter().skip(1).fold(prims[0].1, |acc, p| surrounding_box(&acc, &p.1));
+        BuildNode::Leaf { shapes: prims.into_iter().map(|p| p.0).collect(), bbox }
+    }
+
+    fn branch(left: BuildNode, right: BuildNode, axis: usize) -> BuildNode {
+        let bbox = surrounding_box(&left.bbox(), &right.bbox());
+        BuildNode::Branch { left: Box::new(left), right: Box::new(right), axis, bbox }
+    }
+
+    //分割軸はランダム、個数の中央で分割
+    fn build_random(mut prims: Vec<Primitive>, depth: usize) -> BuildNode {
+        fn box_compare(axis: usize) -> impl FnMut(&Primitive, &Primitive) -> Ordering {
             move |a, b| {
-                let a_bbox = a.bounding_box();
-                let b_bbox = b.bounding_box();
-                if let (Some(a), Some(b)) = (a_bbox, b_bbox) {
-                    let ac = a.min[axis] + a.max[axis];
-                    let bc = b.min[axis] + b.max[axis];
-                    ac.partial_cmp(&bc).unwrap()
-                } else {
-                    panic!["no bounding box in bvh node"]
-                }
+                let ac = a.1.min[axis] + a.1.max[axis];
+                let bc = b.1.min[axis] + b.1.max[axis];
+                ac.partial_cmp(&bc).unwrap()
             }
         }
 
         let axis_random = random();
-        let axis: usize;
-        if axis_random < 0.33 {
-            axis = 0
+        let axis: usize = if axis_random < 0.33 {
+            0
         } else if axis_random < 0.66 {
-            axis = 1
+            1
         } else {
-            axis = 2
+            2
         };
 
-        shape.sort_unstable_by(box_compare(axis));
-        let len = shape.len();
+        prims.sort_unstable_by(box_compare(axis));
+        let len = prims.len();
         match len {
             0 => panic!["no elements in scene"],
-            1 => {
-                let leaf = shape.pop().unwrap();
-                if let Some(bbox) = leaf.bounding_box() {
-                    BVH {
-                        tree: BVHNode::Leaf(leaf),
-                        bbox,
-                    }
-                } else {
-                    panic!["no bounding box in bvh node"]
+            1 => Self::leaf(prims),
+            _ if depth >= MAX_DEPTH => Self::leaf(prims),
+            _ => {
+                let right = Self::build_random(prims.drain(len / 2..).collect(), depth + 1);
+                let left = Self::build_random(prims, depth + 1);
+                Self::branch(left, right, axis)
+            }
+        }
+    }
+
+    // 重心をビンに分けてSAHコスト最小の面で分割
+    fn build_sah(mut prims: Vec<Primitive>, depth: usize) -> BuildNode {
+        let len = prims.len();
+        if len == 1 || depth >= MAX_DEPTH {
+            return Self::leaf(prims);
+        }
+        let bbox = prims.iter().skip(1).fold(prims[0].1, |acc, p| surrounding_box(&acc, &p.1));
+        let first = prims[0].1.centroid();
+        let centroid_box = prims
+            .iter()
+            .skip(1)
+            .fold(AABB::new(first, first), |acc, p| {
+                let c = p.1.centroid();
+                surrounding_box(&acc, &AABB::new(c, c))
+            });
+
+        let leaf_cost = SAH_INTERSECT_COST * len as f64;
+        let mut best: Option<(f64, usize, usize)> = None;
+        for axis in 0..3 {
+            let lo = centroid_box.min[axis];
+            let extent = centroid_box.max[axis] - lo;
+            if extent <= 0.0 {
+                continue;
+            }
+            let bin_of = |p: &Primitive| {
+                (((p.1.centroid()[axis] - lo) / extent * SAH_BINS as f64) as usize).min(SAH_BINS - 1)
+            };
+            let mut counts = [0usize; SAH_BINS];
+            let mut boxes: [Option<AABB>; SAH_BINS] = [None; SAH_BINS];
+            for p in &prims {
+                let b = bin_of(p);
+                counts[b] += 1;
+                boxes[b] = Some(match boxes[b] {
+                    Some(acc) => surrounding_box(&acc, &p.1),
+                    None => p.1,
+                });
+            }
+            // 右側から累積した面積と個数
+            let mut right_area = [0.0; SAH_BINS];
+            let mut right_count = [0usize; SAH_BINS];
+            let mut acc: Option<AABB> = None;
+            let mut n = 0;
+            for b in (1..SAH_BINS).rev() {
+                if let Some(bb) = boxes[b] {
+                    acc = Some(match acc {
+                        Some(a) => surrounding_box(&a, &bb),
+                        None => bb,
+                    });
                 }
+                n += counts[b];
+                right_area[b] = acc.map_or(0.0, |a| a.surface_area());
+                right_count[b] = n;
             }
-            _ => {
-                let right = BVH::new(shape.drain(len / 2..).collect());
-                let left = BVH::new(shape);
-                let bbox = surrounding_box(&left.bbox, &right.bbox);
-                BVH {
-                    tree: BVHNode::Branch {
-                        left: Box::new(left),
-                        right: Box::new(right),
-                    },
-                    bbox,
+            let mut acc: Option<AABB> = None;
+            let mut n = 0;
+            for b in 0..SAH_BINS - 1 {
+                if let Some(bb) = boxes[b] {
+                    acc = Some(match acc {
+                        Some(a) => surrounding_box(&a, &bb),
+                        None => bb,
+                    });
+                }
+                n += counts[b];
+                if n == 0 || right_count[b + 1] == 0 {
+                    continue;
                 }
+                let left_area = acc.map_or(0.0, |a| a.surface_area());
+                let cost = SAH_TRAVERSAL_COST
+                    + SAH_INTERSECT_COST
+                        * (left_area * n as f64 + right_area[b + 1] * right_count[b + 1] as f64)
+                        / bbox.surface_area();
+                if best.is_none_or(|(c, _, _)| cost < c) {
+                    best = Some((cost, axis, b));
+                }
+            }
+        }
+
+        match best {
+            Some((cost, axis, split)) if cost < leaf_cost || len > MAX_LEAF_SIZE => {
+                let lo = centroid_box.min[axis];
+                let extent = centroid_box.max[axis] - lo;
+                let (left, right): (Vec<Primitive>, Vec<Primitive>) = prims.into_iter().partition(|p| {
+                    (((p.1.centroid()[axis] - lo) / extent * SAH_BINS as f64) as usize).min(SAH_BINS - 1) <= split
+                });
+                Self::branch(Self::build_sah(left, depth + 1), Self::build_sah(right, depth + 1), axis)
+            }
+            None if len > MAX_LEAF_SIZE => {
+                //重心が全て同じ位置なら個数で半分に
+                let right = prims.split_off(len / 2);
+                Self::branch(Self::build_sah(prims, depth + 1), Self::build_sah(right, depth + 1), 0)
+            }
+            _ => Self::leaf(prims),
+        }
+    }
+
+    pub fn stats(&self) -> BVHStats {
+        fn walk(bvh: &BVH, index: usize, root_area: f64, depth: usize, stats: &mut BVHStats) {
+            let node = &bvh.nodes[index];
+            let area = if root_area > 0.0 { node.bbox.surface_area() / root_area } else { 1.0 };
+            stats.nodes += 1;
+            stats.depth = stats.depth.max(depth);
+            if node.count > 0 {
+                stats.leaves += 1;
+                stats.primitives += node.count;
+                stats.sah_cost += area * SAH_INTERSECT_COST * node.count as f64;
+            } else {
+                stats.sah_cost += area * SAH_TRAVERSAL_COST;
+                walk(bvh, index + 1, root_area, depth + 1, stats);
+                walk(bvh, node.offset, root_area, depth + 1, stats);
             }
         }
+        let mut stats = BVHStats { nodes: 0, leaves: 0, primitives: 0, depth: 0, sah_cost: 0.0 };
+        walk(self, 0, self.nodes[0].bbox.surface_area(), 1, &mut stats);
+        stats
     }
 }
 
 impl Shape for BVH {
     fn hit(&self, ray: &Ray, t_min: f64, mut t_max: f64) -> Option<HitInfo> {
-        if !self.bbox.hit(&ray, t_min, t_max) {
-            return None;
-        }
-        match &self.tree {
-            BVHNode::Leaf(leaf) => leaf.hit(&ray, t_min, t_max),
-            BVHNode::Branch { left, right } => {
-                let left = left.hit(&ray, t_min, t_max);
-                if let Some(l) = &left {
-                    t_max = l.t
-                };
-                let right = right.hit(&ray, t_min, t_max);
-                if right.is_some() { right } else { left }
+        let inv_d = Vec3::new(ray.d.x.recip(), ray.d.y.recip(), ray.d.z.recip());
+        let dir_is_neg = [inv_d.x < 0.0, inv_d.y < 0.0, inv_d.z < 0.0];
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut top = 0;
+        let mut current = 0;
+        let mut hit_info: Option<HitInfo> = None;
+        loop {
+            let node = &self.nodes[current];
+            if node.bbox.hit_inv(ray.o, inv_d, t_min, t_max) {
+                if node.count > 0 {
+                    for shape in &self.shapes[node.offset..node.offset + node.count] {
+                        if let Some(info) = shape.hit(ray, t_min, t_max) {
+                            t_max = info.t;
+                            hit_info = Some(info);
+                        }
+                    }
+                } else {
+                    // レイの向きから近い方の子を先に調べる
+                    if dir_is_neg[node.axis] {
+                        stack[top] = current + 1;
+                        current = node.offset;
+                    } else {
+                        stack[top] = node.offset;
+                        current += 1;
+                    }
+                    top += 1;
+                    continue;
+                }
+            }
+            if top == 0 {
+                break;
             }
+            top -= 1;
+            current = stack[top];
         }
+        hit_info
     }
 
     fn bounding_box(&self) -> Option<AABB> {
-        Some(self.bbox.clone())
+        Some(self.nodes[0].bbox)
+    }
+
+    // 全体を一つの光源とみなし、面積に比例してプリミティブを選ぶ(発光材質で上書きしたインスタンス用)
+    // pdfはレイが箱を通るプリミティブだけ足せばよい
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        let total = self.area();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let ray = Ray::new(o, v);
+        let mut stack = vec![0];
+        let mut pdf = 0.0;
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            if !node.bbox.hit(&ray, EPS10, f64::MAX) {
+                continue;
+            }
+            if node.count > 0 {
+                for shape in &self.shapes[node.offset..node.offset + node.count] {
+                    pdf += shape.area() / total * shape.pdf_value(o, v, time);
+                }
+            } else {
+                stack.push(current + 1);
+                stack.push(node.offset);
+            }
+        }
+        pdf
+    }
+
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        let u = random() * self.area();
+        let i = self.area_cdf.partition_point(|&c| c <= u).min(self.shapes.len() - 1);
+        self.shapes[i].random(o, time)
+    }
+
+    fn emitters(&self) -> Vec<Arc<dyn Shape>> {
+        let mut emitters = Vec::new();
+        for shape in &self.shapes {
+            if shape.is_emissive() {
+                emitters.push(Arc::clone(shape));
+            } else {
+                emitters.extend(shape.emitters());
+            }
+        }
+        emitters
+    }
+
+    fn area(&self) -> f64 {
+        self.area_cdf.last().copied().unwrap_or(0.0)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // 毎回同じ列を返す線形合同法
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> f64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
 
+        fn vec3(&mut self, a: f64, b: f64) -> Vec3 {
+            Vec3::new(a + (b - a) * self.next(), a + (b - a) * self.next(), a + (b - a) * self.next())
+        }
+    }
+
+    // 球・三角形・軸に平行な長方形を混ぜた同じシーンを何度でも作る
+    fn shapes() -> Vec<Box<dyn Shape>> {
+        let mut rng = Lcg(7);
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::full(0.5)))));
+        let mut shapes: Vec<Box<dyn Shape>> = Vec::new();
+        for i in 0..300 {
+            let c = rng.vec3(-10.0, 10.0);
+            let shape: Box<dyn Shape> = match i % 3 {
+                0 => Box::new(Sphere::new(c, 0.1 + rng.next(), Arc::clone(&material))),
+                1 => Box::new(Triangle::from_points(
+                    c,
+                    c + rng.vec3(-1.0, 1.0),
+                    c + rng.vec3(-1.0, 1.0),
+                    Arc::clone(&material),
+                )),
+                _ => Box::new(Rect::new(c.x, c.x + rng.next(), c.y, c.y + rng.next(), c.z, RectAxisType::XY, Arc::clone(&material))),
+            };
+            shapes.push(shape);
+        }
+        shapes
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let list = ShapeList { objects: shapes() };
+        for split in [BVHSplit::Sah, BVHSplit::Random] {
+            let bvh = BVH::new(shapes(), split);
+            assert_eq!(bvh.stats().primitives, list.objects.len());
+            let mut rng = Lcg(11);
+            let mut hits = 0;
+            for i in 0..5000 {
+                //シーン内の点に向けて撃つ。軸に平行なレイも混ぜる(逆数が無限大になる)
+                let o = rng.vec3(-12.0, 12.0);
+                let mut d = rng.vec3(-10.0, 10.0) - o;
+                if i % 4 == 0 {
+                    d[i % 3] = 0.0;
+                }
+                let ray = Ray::new(o, d);
+                let expected = list.hit(&ray, 0.001, f64::MAX).map(|h| h.t);
+                let actual = bvh.hit(&ray, 0.001, f64::MAX).map(|h| h.t);
+                assert_eq!(actual, expected, "{:?} ray {}", split, i);
+                hits += expected.is_some() as usize;
+            }
+            assert!(hits > 1000, "too few rays hit the scene: {}", hits);
+        }
+    }
+
+    #[test]
+    fn whole_bvh_pdf_is_area_weighted_sum() {
+        let bvh = BVH::new(shapes(), BVHSplit::Sah);
+        let list = shapes();
+        let total: f64 = list.iter().map(|s| s.area()).sum();
+        assert!((bvh.area() - total).abs() < 1e-9 * total);
+        let o = Vec3::new(0.0, 0.0, -15.0);
+        for _ in 0..500 {
+            let v = bvh.random(o, 0.0);
+            let expected: f64 = list.iter().map(|s| s.area() / total * s.pdf_value(o, v, 0.0)).sum();
+            let actual = bvh.pdf_value(o, v, 0.0);
+            assert!(expected > 0.0);
+            assert!((actual - expected).abs() < 1e-9 * expected, "{} != {}", actual, expected);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::raymod::*;
+
+use std::fs;
+use std::path::Path;
+
+// 区分定数の1次元分布。funcの値に比例して[0,1)の値を選ぶ
+pub struct Distribution1D {
+    pub func: Vec<f64>,
+    pub cdf: Vec<f64>,
+    pub integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f64;
+        }
+        let integral = cdf[n];
+        if integral > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        } else {
+            //全部0なら一様に選ぶ
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        }
+        Self { func, cdf, integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Sample a continuous value in [0,1); returns (value, pdf, bin index)
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        //cdf[i] <= u となる最後のi
+        let i = self.cdf.partition_point(|&c| c <= u).clamp(1, self.count()) - 1;
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let du = if width > 0.0 { (u - self.cdf[i]) / width } else { 0.0 };
+        ((i as f64 + du) / self.count() as f64, self.pdf(i), i)
+    }
+
+    /// Density of bin `i` with respect to [0,1)
+    pub fn pdf(&self, i: usize) -> f64 {
+        if self.integral > 0.0 { self.func[i] / self.integral } else { 1.0 }
+    }
+}
+
+// 行ごとの条件付き分布と、行を選ぶ周辺分布
+pub struct Distribution2D {
+    pub conditional: Vec<Distribution1D>,
+    pub marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `func` is row-major with `width` columns
+    pub fn new(func: &[f64], width: usize, height: usize) -> Self {
+        let conditional: Vec<Distribution1D> =
+            (0..height).map(|y| Distribution1D::new(func[y * width..(y + 1) * width].to_vec())).collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral).collect());
+        Self { conditional, marginal }
+    }
+
+    /// Sample (u, v) in [0,1)^2; returns the point and its pdf
+    pub fn sample(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, y) = self.marginal.sample(u1);
+        let (u, pdf_u, _) = self.conditional[y].sample(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let y = ((v * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        let row = &self.conditional[y];
+        let x = ((u * row.count() as f64) as usize).min(row.count() - 1);
+        if self.marginal.integral > 0.0 { row.func[x] / self.marginal.integral } else { 1.0 }
+    }
+}
+
+/// Direction for image coordinates (u, v) in [0,1)^2 of an unrotated equirectangular map
+pub fn equirect_direction(u: f64, v: f64) -> Vec3 {
+    let (sin_theta, cos_theta) = (v * PI).sin_cos();
+    let (sin_phi, cos_phi) = (u * 2.0 * PI).sin_cos();
+    Vec3::new(sin_theta * cos_phi, cos_theta, sin_theta * sin_phi)
+}
+
+// 正距円筒図法の環境マップ。画像の上端が+Y、u=0が+X方向
+// 輝度×sinθに比例した2次元分布で方向をサンプリングする
+pub struct EnvMap {
+    pub pixels: Vec<Color>,
+    pub width: usize,
+    pub height: usize,
+    pub intensity: f64,
+    pub rotation: Transform,
+    pub distribution: Distribution2D,
+}
+
+impl EnvMap {
+    /// `rotation` is in degrees about the +Y axis
+    pub fn new(pixels: Vec<Color>, width: usize, height: usize, intensity: f64, rotation: f64) -> Self {
+        let mut func = vec![0.0; width * height];
+        for y in 0..height {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0..width {
+                let c = pixels[y * width + x];
+                func[y * width + x] = (0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z).max(0.0) * sin_theta;
+            }
+        }
+        let distribution = Distribution2D::new(&func, width, height);
+        let rotation = Transform::rotate(Vec3::new(0.0, 1.0, 0.0), rotation);
+        Self { pixels, width, height, intensity, rotation, distribution }
+    }
+
+    /// Load a Radiance `.hdr`, OpenEXR or PFM image (other formats are read as sRGB)
+    pub fn load(path: &str, intensity: f64, rotation: f64) -> Result<Self, String> {
+        let is_pfm = Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case("pfm"));
+        let (pixels, width, height) = if is_pfm {
+            load_pfm(path)?
+        } else {
+            let img = image::open(path).map_err(|e| e.to_string())?.into_rgb32f();
+            let (w, h) = img.dimensions();
+            let pixels = img.pixels().map(|p| Color::new(p[0] as f64, p[1] as f64, p[2] as f64)).collect();
+            (pixels, w as usize, h as usize)
+        };
+        if width == 0 || height == 0 {
+            return Err("empty image".to_string());
+        }
+        Ok(Self::new(pixels, width, height, intensity, rotation))
+    }
+
+    // ワールドの方向 -> 画像の(u,v)
+    fn direction_to_uv(&self, d: Vec3) -> (f64, f64) {
+        let d = self.rotation.inverse().vector(d).norm();
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+        let phi = d.z.atan2(d.x);
+        let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+        ((phi / (2.0 * PI)).min(1.0 - f64::EPSILON), theta / PI)
+    }
+
+    fn uv_to_direction(&self, u: f64, v: f64) -> (Vec3, f64) {
+        (self.rotation.vector(equirect_direction(u, v)), (v * PI).sin())
+    }
+
+    /// Radiance arriving from direction `d`
+    pub fn radiance(&self, d: Vec3) -> Color {
+        let (u, v) = self.direction_to_uv(d);
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+
+    /// Sample a direction proportional to luminance; returns (direction, solid angle pdf)
+    pub fn sample(&self) -> (Vec3, f64) {
+        let ((u, v), pdf) = self.distribution.sample(random(), random());
+        let (d, sin_theta) = self.uv_to_direction(u, v);
+        if sin_theta <= 0.0 {
+            return (d, 0.0);
+        }
+        //(u,v)の密度から立体角の密度へ。dω = 2π² sinθ du dv
+        (d, pdf / (2.0 * PI * PI * sin_theta))
+    }
+
+    pub fn pdf_value(&self, d: Vec3) -> f64 {
+        let (u, v) = self.direction_to_uv(d);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+// Portable FloatMap。スケールが負ならリトルエンディアン、行は下から上
+fn load_pfm(path: &str) -> Result<(Vec<Color>, usize, usize), String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    //ヘッダは空白区切りの4トークン(種類・幅・高さ・スケール)+1バイトの区切り
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err("truncated PFM header".to_string());
+        }
+        tokens.push(String::from_utf8_lossy(&data[start..pos]).to_string());
+    }
+    pos += 1;
+    let channels = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(format!("not a PFM file (magic '{}')", tokens[0])),
+    };
+    let parse = |s: &str| s.parse::<f64>().map_err(|_| format!("invalid PFM header value '{}'", s));
+    let width = parse(&tokens[1])? as usize;
+    let height = parse(&tokens[2])? as usize;
+    let little_endian = parse(&tokens[3])? < 0.0;
+
+    let body = data.get(pos..).unwrap_or(&[]);
+    if body.len() < width * height * channels * 4 {
+        return Err(format!("PFM data too short for {}x{}", width, height));
+    }
+    let value = |i: usize| {
+        let b = [body[i * 4], body[i * 4 + 1], body[i * 4 + 2], body[i * 4 + 3]];
+        (if little_endian { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }) as f64
+    };
+    let mut pixels = vec![Color::zero(); width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let i = ((height - 1 - y) * width + x) * channels;
+            pixels[y * width + x] =
+                if channels == 3 { Color::new(value(i), value(i + 1), value(i + 2)) } else { Color::full(value(i)) };
+        }
+    }
+    Ok((pixels, width, height))
+}
@@ -0,0 +1,65 @@
+use crate::raymod::*;
+use std::sync::Arc;
+
+// 共有するBVH(下位)を変換して配置する
+// 同じメッシュを何個置いてもジオメトリは一つだけ
+pub struct Instance {
+    pub object: Arc<BVH>,
+    pub transform: Transform,
+    pub material: Option<Arc<dyn Material>>,
+}
+
+impl Instance {
+    pub fn new(object: Arc<BVH>, transform: Transform, material: Option<Arc<dyn Material>>) -> Self {
+        Self { object, transform, material }
+    }
+}
+
+impl Shape for Instance {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let local_ray = self.transform.inverse().ray(ray);
+        let hit = self.object.hit(&local_ray, t0, t1)?;
+        let m = match &self.material {
+            Some(m) => Arc::clone(m),
+            None => hit.m,
+        };
+        Some(HitInfo { p: self.transform.point(hit.p), n: self.transform.normal(hit.n).norm(), m, ..hit })
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        self.object.bounding_box().map(|b| self.transform.aabb(&b))
+    }
+
+    // 発光材質で上書きしたインスタンスはメッシュ全体が一つの光源になる
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.transform.shape_pdf(self.object.as_ref(), o, v, time)
+    }
+
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.transform.shape_random(self.object.as_ref(), o, time)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.as_ref().is_some_and(|m| m.is_emissive()) && self.object.area() > 0.0
+    }
+
+    // 上書きしなければメッシュ内の発光する面をこの変換で置いたもの
+    fn emitters(&self) -> Vec<Arc<dyn Shape>> {
+        if self.material.is_some() {
+            return Vec::new();
+        }
+        self.object
+            .emitters()
+            .into_iter()
+            .map(|e| Arc::new(Transformed::new(Box::new(e), self.transform)) as Arc<dyn Shape>)
+            .collect()
+    }
+
+    fn area(&self) -> f64 {
+        self.transform.shape_area(self.object.as_ref())
+    }
+
+    fn emission(&self) -> Color {
+        self.material.as_ref().map_or(Color::zero(), |m| m.emission())
+    }
+}
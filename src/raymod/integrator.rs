@@ -0,0 +1,200 @@
+use crate::raymod::*;
+use std::sync::Arc;
+
+// シーンのデータからレイの色(放射輝度)を求める
+pub trait Integrator: Send + Sync {
+    fn radiance(&self, scene: &dyn Scene, ray: &Ray) -> Color;
+}
+
+// 光源があれば各頂点で光源へのシャドウレイ(NEE)とBSDFサンプリングを行い、
+// パワーヒューリスティックで重み付けして足す(MIS)。環境マップも同様に扱う
+// 点光源などは全て直接サンプリングする
+// 再帰せずにスループットを掛けていき、rr_depth以降はロシアンルーレットで打ち切る
+// spectralならパスごとに波長を選び、RGBの値は全てその波長での値に直して運ぶ
+pub struct PathTracer {
+    pub max_depth: i64,
+    pub rr_depth: i64,
+    pub spectral: bool,
+}
+
+impl PathTracer {
+    pub const fn new(max_depth: i64, rr_depth: i64) -> Self {
+        Self { max_depth, rr_depth, spectral: false }
+    }
+    pub const fn with_spectral(self, spectral: bool) -> Self {
+        Self { spectral, ..self }
+    }
+
+    // 光源上の点へシャドウレイを飛ばし、最初に当たった面の放射を取る
+    #[allow(clippy::too_many_arguments)]
+    fn sample_light(
+        &self,
+        scene: &dyn Scene,
+        light: &Arc<dyn Shape>,
+        r: &Ray,
+        hit: &HitInfo,
+        scatter: &ScatterInfo,
+        bsdf: &Arc<dyn Pdf>,
+        wl: &Wavelengths,
+    ) -> Color {
+        let light_pdf = ShapePdf::new(Arc::clone(light), hit.p, r.time);
+        let shadow_ray = Ray::with_time(hit.p, light_pdf.generate(hit), r.time);
+        let pdf = light_pdf.value(hit, shadow_ray.d);
+        if pdf <= 0.0 {
+            return Color::zero();
+        }
+        let f = hit.m.bsdf(r, hit, scatter, &shadow_ray);
+        if f.x + f.y + f.z <= 0.0 {
+            return Color::zero();
+        }
+        match scene.world().hit(&shadow_ray, EPS, f64::MAX) {
+            Some(light_hit) => {
+                let emitted = light_hit.m.emitted(&shadow_ray, &light_hit);
+                wl.upsample(f).mult(wl.upsample(emitted)) * power_heuristic(pdf, bsdf.value(hit, shadow_ray.d)) / pdf
+            }
+            None => Color::zero(),
+        }
+    }
+
+    // 環境マップから方向を選び、何にも遮られなければその放射を取る
+    #[allow(clippy::too_many_arguments)]
+    fn sample_environment(
+        &self,
+        scene: &dyn Scene,
+        env: &EnvMap,
+        r: &Ray,
+        hit: &HitInfo,
+        scatter: &ScatterInfo,
+        bsdf: &Arc<dyn Pdf>,
+        wl: &Wavelengths,
+    ) -> Color {
+        let (dir, pdf) = env.sample();
+        if pdf <= 0.0 {
+            return Color::zero();
+        }
+        let shadow_ray = Ray::with_time(hit.p, dir, r.time);
+        let f = hit.m.bsdf(r, hit, scatter, &shadow_ray);
+        if f.x + f.y + f.z <= 0.0 || scene.world().hit(&shadow_ray, EPS, f64::MAX).is_some() {
+            return Color::zero();
+        }
+        wl.upsample(f).mult(wl.upsample(env.radiance(dir))) * power_heuristic(pdf, bsdf.value(hit, dir)) / pdf
+    }
+
+    // デルタ光源はBSDFのレイが当たることはないのでMISせずにそのまま足す
+    fn sample_punctual(
+        &self,
+        scene: &dyn Scene,
+        light: &dyn Light,
+        r: &Ray,
+        hit: &HitInfo,
+        scatter: &ScatterInfo,
+        wl: &Wavelengths,
+    ) -> Color {
+        let Some(sample) = light.sample(hit.p) else { return Color::zero() };
+        let shadow_ray = Ray::with_time(hit.p, sample.dir, r.time);
+        let f = hit.m.bsdf(r, hit, scatter, &shadow_ray);
+        if f.x + f.y + f.z <= 0.0 || scene.world().hit(&shadow_ray, EPS, sample.dist - EPS10).is_some() {
+            return Color::zero();
+        }
+        wl.upsample(f).mult(wl.upsample(sample.radiance))
+    }
+}
+
+/// Power heuristic (beta = 2) weight for a sample drawn from `pdf_a`
+pub fn power_heuristic(pdf_a: f64, pdf_b: f64) -> f64 {
+    let a = pdf_a * pdf_a;
+    let b = pdf_b * pdf_b;
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, scene: &dyn Scene, ray: &Ray) -> Color {
+        let mut radiance = Color::zero();
+        let mut throughput = Color::full(1.0);
+        let mut wl = if self.spectral { Wavelengths::sample() } else { Wavelengths::rgb() };
+        let mut r = ray.with_wavelength(wl.hero());
+        //直前の頂点のBSDFのpdfと位置。鏡面やカメラからのレイはMISしない
+        let mut specular = true;
+        let mut prev_pdf = 0.0;
+        let mut prev_p = Vec3::zero();
+        //max_depthまで反射しても終わらなかったパスは寄与なし
+        for depth in 0..self.max_depth {
+            let hit = match scene.world().hit(&r, EPS, f64::MAX) {
+                Some(h) => h,
+                None => {
+                    let background = match scene.environment() {
+                        Some(env) => {
+                            let weight = if specular { 1.0 } else { power_heuristic(prev_pdf, env.pdf_value(r.d)) };
+                            env.radiance(r.d) * weight
+                        }
+                        None => scene.background(),
+                    };
+                    //視直径のある平行光源はNEEでしか数えないので、鏡面経由で見えた時だけ足す
+                    let background = if specular {
+                        scene.punctual_lights().iter().fold(background, |c, light| c + light.emitted(r.d))
+                    } else {
+                        background
+                    };
+                    radiance = radiance + throughput.mult(wl.upsample(background));
+                    break;
+                }
+            };
+            let emitted = hit.m.emitted(&r, &hit);
+            if emitted.x + emitted.y + emitted.z > 0.0 {
+                //NEEでも数えている光源なら重みを付けて二重に数えない
+                let weight = match scene.light() {
+                    Some(light) if !specular => power_heuristic(prev_pdf, light.pdf_value(prev_p, r.d, r.time)),
+                    _ => 1.0,
+                };
+                radiance = radiance + throughput.mult(wl.upsample(emitted)) * weight;
+            }
+            //波長で屈折の向きが変わるなら、ここから先は代表波長だけを追う
+            if hit.m.is_dispersive() {
+                throughput = wl.terminate_secondary(throughput);
+            }
+            let scatter = match hit.m.scatter(&r, &hit) {
+                Some(s) => s,
+                None => break,
+            };
+            let (new_ray, weight) = match &scatter.pdf {
+                Some(pdf) => {
+                    if let Some(light) = scene.light() {
+                        radiance = radiance + throughput.mult(self.sample_light(scene, light, &r, &hit, &scatter, pdf, &wl));
+                    }
+                    if let Some(env) = scene.environment() {
+                        radiance = radiance + throughput.mult(self.sample_environment(scene, env, &r, &hit, &scatter, pdf, &wl));
+                    }
+                    for light in scene.punctual_lights() {
+                        radiance = radiance + throughput.mult(self.sample_punctual(scene, light.as_ref(), &r, &hit, &scatter, &wl));
+                    }
+                    let new_ray = Ray::with_time(hit.p, pdf.generate(&hit), r.time);
+                    let bsdf_pdf = pdf.value(&hit, new_ray.d);
+                    if bsdf_pdf <= 0.0 {
+                        break;
+                    }
+                    specular = false;
+                    prev_pdf = bsdf_pdf;
+                    prev_p = hit.p;
+                    let weight = hit.m.bsdf(&r, &hit, &scatter, &new_ray) / bsdf_pdf;
+                    (new_ray, weight)
+                }
+                //鏡面反射・屈折
+                None => {
+                    specular = true;
+                    (scatter.ray, scatter.albedo)
+                }
+            };
+            throughput = throughput.mult(wl.upsample(weight));
+            if depth + 1 >= self.rr_depth {
+                //生き残ったパスを1/qで重み付けすれば期待値は変わらない
+                let q = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+                if q <= 0.0 || random() >= q {
+                    break;
+                }
+                throughput = throughput / q;
+            }
+            r = new_ray.with_wavelength(wl.hero());
+        }
+        wl.to_rgb(radiance)
+    }
+}
@@ -0,0 +1,329 @@
+use crate::raymod::*;
+
+use std::sync::Arc;
+
+// 複数光源から一つを選ぶ方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightSampling {
+    Uniform,
+    Power,
+    Bvh,
+}
+
+/// Build a light sampler over `lights` that picks one with `sampling`
+pub fn build_lights(lights: Vec<Box<dyn Shape>>, sampling: LightSampling) -> Arc<dyn Shape> {
+    match sampling {
+        LightSampling::Uniform => Arc::new(ShapeList { objects: lights }),
+        LightSampling::Power => Arc::new(PowerLights::new(lights)),
+        LightSampling::Bvh => Arc::new(LightBVH::new(lights)),
+    }
+}
+
+/// Estimated emitted power (area times average luminance) used to weight a light
+pub fn light_power(light: &dyn Shape) -> f64 {
+    let e = light.emission();
+    let luminance = 0.2126 * e.x + 0.7152 * e.y + 0.0722 * e.z;
+    //見積もりが0でも選ばれる確率は残す(MISで補われる)
+    (light.area() * luminance).max(f64::MIN_POSITIVE)
+}
+
+fn lights_hit(lights: &[Box<dyn Shape>], ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+    let mut hit_info: Option<HitInfo> = None;
+    let mut closest_so_far = t1;
+    for light in lights {
+        if let Some(info) = light.hit(ray, t0, closest_so_far) {
+            closest_so_far = info.t;
+            hit_info = Some(info);
+        }
+    }
+    hit_info
+}
+
+fn lights_bbox(lights: &[Box<dyn Shape>]) -> Option<AABB> {
+    lights
+        .iter()
+        .map(|l| l.bounding_box())
+        .reduce(|a, b| match (a, b) {
+            (Some(a), Some(b)) => Some(surrounding_box(&a, &b)),
+            _ => None,
+        })
+        .flatten()
+}
+
+// 放射パワーに比例して光源を選ぶ
+pub struct PowerLights {
+    pub lights: Vec<Box<dyn Shape>>,
+    pub cdf: Vec<f64>,
+}
+
+impl PowerLights {
+    pub fn new(lights: Vec<Box<dyn Shape>>) -> Self {
+        if lights.is_empty() {
+            panic!["no lights"]
+        }
+        let mut total = 0.0;
+        let mut cdf: Vec<f64> = lights
+            .iter()
+            .map(|l| {
+                total += light_power(l.as_ref());
+                total
+            })
+            .collect();
+        for c in cdf.iter_mut() {
+            *c /= total;
+        }
+        Self { lights, cdf }
+    }
+
+    fn probability(&self, i: usize) -> f64 {
+        if i == 0 { self.cdf[0] } else { self.cdf[i] - self.cdf[i - 1] }
+    }
+}
+
+impl Shape for PowerLights {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        lights_hit(&self.lights, ray, t0, t1)
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        lights_bbox(&self.lights)
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.lights
+            .iter()
+            .enumerate()
+            .fold(0.0, |acc, (i, l)| acc + self.probability(i) * l.pdf_value(o, v, time))
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        let u = random();
+        let i = self.cdf.partition_point(|&c| c < u).min(self.lights.len() - 1);
+        self.lights[i].random(o, time)
+    }
+}
+
+// 光源のBVH。ノードごとにパワーと距離から寄与を見積もり、確率的に子を選んで降りる
+struct LightNode {
+    bbox: AABB,
+    power: f64,
+    //葉なら光源の番号、節なら右の子の番号(左の子は次のノード)
+    index: usize,
+    leaf: bool,
+}
+
+pub struct LightBVH {
+    nodes: Vec<LightNode>,
+    lights: Vec<Box<dyn Shape>>,
+}
+
+impl LightBVH {
+    pub fn new(lights: Vec<Box<dyn Shape>>) -> Self {
+        if lights.is_empty() {
+            panic!["no lights"]
+        }
+        let mut items: Vec<(usize, AABB, f64)> = lights
+            .iter()
+            .enumerate()
+            .map(|(i, l)| match l.bounding_box() {
+                Some(bbox) => (i, bbox, light_power(l.as_ref())),
+                None => panic!["no bounding box in light"],
+            })
+            .collect();
+        let mut bvh = Self { nodes: Vec::with_capacity(lights.len() * 2), lights: Vec::new() };
+        bvh.build(&mut items);
+        bvh.lights = lights;
+        bvh
+    }
+
+    // 重心の広がりが最大の軸で半分に分ける
+    fn build(&mut self, items: &mut [(usize, AABB, f64)]) {
+        let bbox = items.iter().skip(1).fold(items[0].1, |acc, it| surrounding_box(&acc, &it.1));
+        let power = items.iter().map(|it| it.2).sum();
+        let index = self.nodes.len();
+        if items.len() == 1 {
+            self.nodes.push(LightNode { bbox, power, index: items[0].0, leaf: true });
+            return;
+        }
+        self.nodes.push(LightNode { bbox, power, index: 0, leaf: false });
+        let (mut cmin, mut cmax) = (Vec3::full(f64::INFINITY), Vec3::full(-f64::INFINITY));
+        for it in items.iter() {
+            let c = it.1.centroid();
+            for a in 0..3 {
+                cmin[a] = cmin[a].min(c[a]);
+                cmax[a] = cmax[a].max(c[a]);
+            }
+        }
+        let extent = cmax - cmin;
+        let axis = if extent.x > extent.y && extent.x > extent.z { 0 } else if extent.y > extent.z { 1 } else { 2 };
+        items.sort_by(|a, b| a.1.centroid()[axis].total_cmp(&b.1.centroid()[axis]));
+        let mid = items.len() / 2;
+        let (left, right) = items.split_at_mut(mid);
+        self.build(left);
+        self.nodes[index].index = self.nodes.len();
+        self.build(right);
+    }
+
+    // 点oから見たノードの寄与の見積もり。箱の中なら箱の大きさで距離を抑える
+    fn importance(&self, node: &LightNode, o: Vec3) -> f64 {
+        let half = (node.bbox.max - node.bbox.min) * 0.5;
+        let d2 = (node.bbox.centroid() - o).length().max(half.length());
+        node.power / d2.max(f64::MIN_POSITIVE)
+    }
+
+    // 二つの子を選ぶ確率
+    fn child_probabilities(&self, node: usize, o: Vec3) -> (usize, usize, f64) {
+        let (left, right) = (node + 1, self.nodes[node].index);
+        let il = self.importance(&self.nodes[left], o);
+        let ir = self.importance(&self.nodes[right], o);
+        (left, right, il / (il + ir))
+    }
+
+    // 方向vのレイが通る箱だけ降りて、選ぶ確率×光源のpdfを足す
+    fn pdf_node(&self, node: usize, o: Vec3, v: Vec3, time: f64, prob: f64) -> f64 {
+        let n = &self.nodes[node];
+        if !n.bbox.hit(&Ray::new(o, v), 0.0, f64::MAX) {
+            return 0.0;
+        }
+        if n.leaf {
+            return prob * self.lights[n.index].pdf_value(o, v, time);
+        }
+        let (left, right, p) = self.child_probabilities(node, o);
+        self.pdf_node(left, o, v, time, prob * p) + self.pdf_node(right, o, v, time, prob * (1.0 - p))
+    }
+}
+
+impl Shape for LightBVH {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        lights_hit(&self.lights, ray, t0, t1)
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        Some(self.nodes[0].bbox)
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.pdf_node(0, o, v, time, 1.0)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        let mut node = 0;
+        while !self.nodes[node].leaf {
+            let (left, right, p) = self.child_probabilities(node, o);
+            node = if random() < p { left } else { right };
+        }
+        self.lights[self.nodes[node].index].random(o, time)
+    }
+}
+
+// 点光源・スポットライト・平行光源。形状を持たないので光源サンプリングでしか寄与しない
+pub struct LightSample {
+    /// Unit direction from the shading point toward the light
+    pub dir: Vec3,
+    /// Distance to the light, infinite for directional lights
+    pub dist: f64,
+    /// Incident radiance already divided by the sampling pdf
+    pub radiance: Color,
+}
+
+pub trait Light: Send + Sync {
+    /// Sample the light as seen from `p`, None if it does not reach `p`
+    fn sample(&self, p: Vec3) -> Option<LightSample>;
+    /// Radiance seen by a ray escaping in direction `d`; only lights with an angular size are visible
+    fn emitted(&self, _d: Vec3) -> Color { Color::zero() }
+}
+
+pub struct PointLight {
+    pub position: Vec3,
+    pub intensity: Color,
+}
+
+impl PointLight {
+    pub const fn new(position: Vec3, intensity: Color) -> Self {
+        Self { position, intensity }
+    }
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let d = self.position - p;
+        let dist2 = d.length();
+        let dist = dist2.sqrt();
+        Some(LightSample { dir: d / dist, dist, radiance: self.intensity / dist2 })
+    }
+}
+
+// 内側の角度までは一定、外側の角度にかけてなめらかに暗くなる
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub intensity: Color,
+    pub cos_inner: f64,
+    pub cos_outer: f64,
+}
+
+impl SpotLight {
+    /// Angles are half-angles of the cone in degrees
+    pub fn new(position: Vec3, direction: Vec3, intensity: Color, inner: f64, outer: f64) -> Self {
+        let cos_outer = outer.to_radians().cos();
+        let cos_inner = inner.min(outer).to_radians().cos();
+        Self { position, direction: direction.norm(), intensity, cos_inner, cos_outer }
+    }
+
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_inner {
+            return 1.0;
+        }
+        if cos_theta <= self.cos_outer {
+            return 0.0;
+        }
+        let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let d = self.position - p;
+        let dist2 = d.length();
+        let dist = dist2.sqrt();
+        let dir = d / dist;
+        let falloff = self.falloff((-dir).dot(&self.direction));
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(LightSample { dir, dist, radiance: self.intensity * falloff / dist2 })
+    }
+}
+
+// 太陽のような平行光源。intensityは光に垂直な面の放射照度
+// 視直径を与えると円錐内の方向を一様に選ぶので影が柔らかくなる
+pub struct DirectionalLight {
+    pub direction: Vec3,
+    pub intensity: Color,
+    pub cos_max: f64,
+}
+
+impl DirectionalLight {
+    /// `direction` is where the light travels; `angle` is the angular diameter in degrees
+    pub fn new(direction: Vec3, intensity: Color, angle: f64) -> Self {
+        Self { direction: direction.norm(), intensity, cos_max: (angle * 0.5).to_radians().cos() }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _p: Vec3) -> Option<LightSample> {
+        let to_light = -self.direction;
+        let dir = if self.cos_max < 1.0 {
+            let z = 1.0 - random() * (1.0 - self.cos_max);
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            let (x, y) = (2.0 * PI * random()).sin_cos();
+            ONB::new(to_light).local(Vec3::new(x * r, y * r, z))
+        } else {
+            to_light
+        };
+        //円錐内の放射輝度E/Ωをpdf 1/Ωで割るとEになる
+        Some(LightSample { dir, dist: f64::INFINITY, radiance: self.intensity })
+    }
+
+    fn emitted(&self, d: Vec3) -> Color {
+        if self.cos_max >= 1.0 || (-d.norm()).dot(&self.direction) < self.cos_max {
+            return Color::zero();
+        }
+        self.intensity / (2.0 * PI * (1.0 - self.cos_max))
+    }
+}
@@ -30,22 +30,42 @@ impl Pdf for CosinePdf {
     }
 }
 
+//全方向一様。等方性散乱用でhit.nには依存しない
+pub struct SpherePdf {}
+
+impl SpherePdf {
+    pub const fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Pdf for SpherePdf {
+    fn value(&self, _hit: &HitInfo, _direction: Vec3) -> f64 {
+        0.25 * FRAC_1_PI
+    }
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        Vec3::random_unit_vector()
+    }
+}
+
 pub struct MixturePdf {
     pub pdfs: [Arc<dyn Pdf>; 2],
+    //pdfs[0]を選ぶ確率
+    pub weight: f64,
 }
 impl MixturePdf {
-    pub fn new(pdf0: Arc<dyn Pdf>, pdf1: Arc<dyn Pdf>) -> Self {
-        Self { pdfs: [pdf0, pdf1] }
+    pub fn weighted(pdf0: Arc<dyn Pdf>, pdf1: Arc<dyn Pdf>, weight: f64) -> Self {
+        Self { pdfs: [pdf0, pdf1], weight }
     }
 }
 impl Pdf for MixturePdf {
     fn value(&self, hit: &HitInfo, direction: Vec3) -> f64 {
-        let pdf0 = self.pdfs[0].value(&hit, direction);
-        let pdf1 = self.pdfs[1].value(&hit, direction);
-        0.5 * pdf0 + 0.5 * pdf1
+        let pdf0 = self.pdfs[0].value(hit, direction);
+        let pdf1 = self.pdfs[1].value(hit, direction);
+        self.weight * pdf0 + (1.0 - self.weight) * pdf1
     }
     fn generate(&self, hit: &HitInfo) -> Vec3 {
-        if random() < 0.5 {
+        if random() < self.weight {
             self.pdfs[0].generate(hit)
         } else {
             self.pdfs[1].generate(hit)
@@ -56,18 +76,20 @@ impl Pdf for MixturePdf {
 pub struct ShapePdf {
     pub shape: Arc<dyn Shape>,
     pub origin: Vec3,
+    //動く光源はこの時刻の位置でサンプリングする
+    pub time: f64,
 }
 impl ShapePdf {
-    pub fn new(shape: Arc<dyn Shape>, origin: Vec3) -> Self {
-        Self { shape, origin }
+    pub fn new(shape: Arc<dyn Shape>, origin: Vec3, time: f64) -> Self {
+        Self { shape, origin, time }
     }
 }
 impl Pdf for ShapePdf {
     fn value(&self, _hit: &HitInfo, direction: Vec3) -> f64 {
-        self.shape.pdf_value(self.origin, direction)
+        self.shape.pdf_value(self.origin, direction, self.time)
     }
     fn generate(&self, _hit: &HitInfo) -> Vec3 {
-        self.shape.random(self.origin)
+        self.shape.random(self.origin, self.time)
     }
 }
 
@@ -75,11 +97,19 @@ pub struct ScatterInfo {
     pub ray: Ray,
     pub albedo: Color,
     pub pdf:Option<Arc<dyn Pdf>>,
+    //重ねたマテリアルの下地で選ばれた散乱。bsdfで同じものを評価する
+    pub base: Option<Box<ScatterInfo>>,
 }
 
 impl ScatterInfo {
     pub fn new(ray: Ray, albedo: Vec3,pdf:Option<Arc<dyn Pdf>>) -> Self {
-        Self { ray, albedo, pdf }
+        Self { ray, albedo, pdf, base: None }
+    }
+
+    /// Keep the scatter chosen by a layered material's base for `Material::bsdf`
+    pub fn with_base(mut self, base: ScatterInfo) -> Self {
+        self.base = Some(Box::new(base));
+        self
     }
 }
 
@@ -88,12 +118,29 @@ pub trait Material: Sync + Send {
     fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo>;
     fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {Color::zero()}
     fn scattering_pdf(&self, _ray: &Ray, _hit: &HitInfo) -> f64 { 0.0 }
+    /// BSDF times cosine for light leaving along `scattered` when `ray` arrived at `hit`
+    fn bsdf(&self, ray: &Ray, hit: &HitInfo, scatter: &ScatterInfo, scattered: &Ray) -> Color {
+        scatter.albedo * self.scattering_pdf(scattered, hit)
+    }
+    /// True if `emitted` can be non-zero; such shapes are sampled as lights
+    fn is_emissive(&self) -> bool { false }
+    /// Average emitted radiance, used to weight lights by power
+    fn emission(&self) -> Color { Color::zero() }
+    /// True if scattering depends on `Ray::wavelength`
+    fn is_dispersive(&self) -> bool { false }
 }
 
 pub trait Texture: Sync + Send {
     fn value(&self, u: f64, v: f64, p: Vec3) -> Color;
 }
 
+//名前付きテクスチャを複数のマテリアルで共有する
+impl Texture for Arc<dyn Texture> {
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Color {
+        self.as_ref().value(u, v, p)
+    }
+}
+
 pub struct ColorTexture {
     color: Vec3,
 }
@@ -139,18 +186,18 @@ pub struct ImageTexture {
 }
 
 impl ImageTexture {
-    pub fn new(path: &str) -> Self {
-        let rgbimg = image::open(path).unwrap().to_rgb8();
+    pub fn load(path: &str) -> Result<Self, image::ImageError> {
+        let rgbimg = image::open(path)?.to_rgb8();
         let (w, h) = rgbimg.dimensions();
         let mut image = vec![Vec3::zero(); (w * h) as usize];
         for (i, (_, _, pixel)) in image.iter_mut().zip(rgbimg.enumerate_pixels()) {
             *i = Color::from_rgb(pixel[0], pixel[1], pixel[2]);
         }
-        Self {
+        Ok(Self {
             pixels: image,
             width: w as usize,
             height: h as usize,
-        }
+        })
     }
 
     pub fn sample(&self, u: i64, v: i64) -> Color {
@@ -200,6 +247,22 @@ impl Material for DiffuseLight {
             Vec3::zero()
         }
     }
+    fn is_emissive(&self) -> bool {
+        true
+    }
+    //テクスチャをUVの格子で平均する
+    fn emission(&self) -> Color {
+        const N: usize = 4;
+        let mut sum = Color::zero();
+        for i in 0..N {
+            for j in 0..N {
+                let u = (i as f64 + 0.5) / N as f64;
+                let v = (j as f64 + 0.5) / N as f64;
+                sum = sum + self.emit.value(u, v, Vec3::zero());
+            }
+        }
+        sum / (N * N) as f64
+    }
 }
 
 
@@ -223,6 +286,53 @@ impl Material for Lambertian {
     }
 }
 
+// Oren-Nayarの粗い拡散面。sigmaは微小面の傾きの標準偏差(ラジアン)で、0ならLambertと同じ
+// サンプリングはLambertと同じコサイン分布
+pub struct OrenNayar {
+    pub albedo: Box<dyn Texture>,
+    pub sigma: Box<dyn Texture>,
+    pub pdf: Arc<dyn Pdf>,
+}
+
+impl OrenNayar {
+    pub fn new(albedo: Box<dyn Texture>, sigma: Box<dyn Texture>) -> Self {
+        Self { albedo, sigma, pdf: Arc::new(CosinePdf::new()) }
+    }
+}
+
+impl Material for OrenNayar {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let albedo = self.albedo.value(hit.u, hit.v, hit.p);
+        Some(ScatterInfo::new(*ray, albedo, Some(Arc::clone(&self.pdf))))
+    }
+    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo) -> f64 {
+        ray.d.norm().dot(&hit.n).max(0.0) * FRAC_1_PI
+    }
+    fn bsdf(&self, ray: &Ray, hit: &HitInfo, scatter: &ScatterInfo, scattered: &Ray) -> Color {
+        let wi = scattered.d.norm();
+        let wo = -ray.d.norm();
+        let cos_i = wi.dot(&hit.n);
+        if cos_i <= 0.0 {
+            return Color::zero();
+        }
+        let cos_o = wo.dot(&hit.n).clamp(0.0, 1.0);
+        let sigma2 = self.sigma.value(hit.u, hit.v, hit.p).x.powi(2);
+        let a = 1.0 - 0.5 * sigma2 / (sigma2 + 0.33);
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+        let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        let sin_o = (1.0 - cos_o * cos_o).max(0.0).sqrt();
+        //接平面に射影した2方向の方位角の差のcos
+        let cos_phi = if sin_i > 1e-4 && sin_o > 1e-4 {
+            ((wi - hit.n * cos_i).dot(&(wo - hit.n * cos_o)) / (sin_i * sin_o)).max(0.0)
+        } else {
+            0.0
+        };
+        //α = max(θi, θo), β = min(θi, θo) で sinα tanβ
+        let sin_tan = if cos_i > cos_o { sin_o * sin_i / cos_i } else { sin_i * sin_o / cos_o.max(1e-4) };
+        scatter.albedo * ((a + b * cos_phi * sin_tan) * cos_i * FRAC_1_PI)
+    }
+}
+
 pub struct Metal {
     pub albedo: Box<dyn Texture>,
     pub fuzz: f64,
@@ -233,26 +343,146 @@ impl Metal {
         Self { albedo, fuzz }
     }
 }
+//fuzzをGGXのαとして使い、albedoを垂直入射の反射率としたSchlickのFresnelを掛ける
 impl Material for Metal {
     fn scatter(&self,ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
-        let mut reflected = ray.d.norm().reflect(hit.n);
-        reflected = reflected + self.fuzz*Vec3::random_hemisphere() ;
+        let albedo = self.albedo.value(hit.u, hit.v, hit.p);
+        let ggx = GGX::new(self.fuzz, self.fuzz);
+        if !ggx.is_smooth() {
+            return Some(ScatterInfo::new(*ray, albedo, Some(Arc::new(GgxPdf::new(ray, hit, ggx)))));
+        }
+        let reflected = ray.d.norm().reflect(hit.n);
         if reflected.dot(&hit.n) > 0.0 {
-            let albedo = self.albedo.value(hit.u, hit.v, hit.p);
-            Some(ScatterInfo::new(Ray::new(hit.p, reflected), albedo,None))
+            Some(ScatterInfo::new(Ray::with_time(hit.p, reflected, ray.time), albedo,None))
         } else {
             None
         }
     }
+    fn bsdf(&self, ray: &Ray, hit: &HitInfo, scatter: &ScatterInfo, scattered: &Ray) -> Color {
+        let (onb, wo) = shading_frame(ray, hit);
+        let wi = onb.to_local(scattered.d.norm());
+        ggx_reflection(&GGX::new(self.fuzz, self.fuzz), wo, wi, |c| fresnel_schlick(c, scatter.albedo))
+    }
+}
+
+// 複素屈折率のFresnelを持つGGXの金属。roughnessが0なら完全鏡面
+pub struct Conductor {
+    pub eta: Color,
+    pub k: Color,
+    pub ggx: GGX,
+}
+
+impl Conductor {
+    pub const fn new(eta: Color, k: Color, ggx: GGX) -> Self {
+        Self { eta, k, ggx }
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        if !self.ggx.is_smooth() {
+            return Some(ScatterInfo::new(*ray, Color::full(1.0), Some(Arc::new(GgxPdf::new(ray, hit, self.ggx)))));
+        }
+        let (onb, wo) = shading_frame(ray, hit);
+        let reflected = ray.d.norm().reflect(onb.w());
+        let albedo = fresnel_conductor(wo.z, self.eta, self.k);
+        Some(ScatterInfo::new(Ray::with_time(hit.p, reflected, ray.time), albedo, None))
+    }
+    fn bsdf(&self, ray: &Ray, hit: &HitInfo, _scatter: &ScatterInfo, scattered: &Ray) -> Color {
+        let (onb, wo) = shading_frame(ray, hit);
+        let wi = onb.to_local(scattered.d.norm());
+        ggx_reflection(&self.ggx, wo, wi, |c| fresnel_conductor(c, self.eta, self.k))
+    }
+}
+
+// 媒質内のBeer-Lambert吸収。進んだ距離に応じて指数的に暗くなる
+#[derive(Copy, Clone, Debug)]
+pub struct Absorption {
+    pub sigma: Color,
+}
+
+impl Absorption {
+    pub fn none() -> Self {
+        Self { sigma: Color::zero() }
+    }
+    /// Medium whose transmittance after travelling `distance` is `color`
+    pub fn from_color(color: Color, distance: f64) -> Self {
+        let sigma = |c: f64| -c.max(1e-6).ln() / distance.max(1e-6);
+        Self { sigma: Color::new(sigma(color.x), sigma(color.y), sigma(color.z)) }
+    }
+    /// Transmittance along `ray` up to `hit`, or white if `ray` arrived from outside
+    pub fn transmittance(&self, ray: &Ray, hit: &HitInfo) -> Color {
+        if ray.d.dot(&hit.n) <= 0.0 {
+            return Color::full(1.0);
+        }
+        let d = hit.t * ray.d.length().sqrt();
+        Color::new((-self.sigma.x * d).exp(), (-self.sigma.y * d).exp(), (-self.sigma.z * d).exp())
+    }
+}
+
+// 波長で変わる屈折率。係数の波長はμm
+#[derive(Copy, Clone, Debug)]
+pub enum Dispersion {
+    None,
+    // n = a + b/λ²
+    Cauchy { a: f64, b: f64 },
+    // n² = 1 + Σ bλ²/(λ² - c)
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    /// Cauchy dispersion with index `ri` at the sodium d-line
+    pub fn cauchy(ri: f64, b: f64) -> Self {
+        Dispersion::Cauchy { a: ri - b / (0.5876 * 0.5876), b }
+    }
+    /// Sellmeier coefficients of common optical materials
+    pub fn preset(name: &str) -> Option<Self> {
+        let (b, c) = match name {
+            "bk7" => ([1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653]),
+            "fused_silica" => ([0.6961663, 0.4079426, 0.8974794], [0.00467914826, 0.0135120631, 97.9340025]),
+            "diamond" => ([0.3306, 4.3356, 0.0], [0.030625, 0.011236, 0.0]),
+            _ => return None,
+        };
+        Some(Dispersion::Sellmeier { b, c })
+    }
+    /// Index of refraction at `wavelength` nm, None if it does not disperse
+    pub fn ior(&self, wavelength: f64) -> Option<f64> {
+        if wavelength <= 0.0 {
+            return None;
+        }
+        let l2 = (wavelength * 1e-3).powi(2);
+        match self {
+            Dispersion::None => None,
+            Dispersion::Cauchy { a, b } => Some(a + b / l2),
+            Dispersion::Sellmeier { b, c } => {
+                Some((1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt())
+            }
+        }
+    }
 }
 
 pub struct Dielectric {
     pub ri: f64,
+    //屈折するたびに掛ける色
+    pub tint: Color,
+    pub absorption: Absorption,
+    //スペクトルモードではriの代わりにこちらを使う
+    pub dispersion: Dispersion,
 }
 
 impl Dielectric {
-    pub const fn new(ri: f64) -> Self {
-        Self { ri }
+    pub fn new(ri: f64) -> Self {
+        Self { ri, tint: Color::full(1.0), absorption: Absorption::none(), dispersion: Dispersion::None }
+    }
+    pub fn with_tint(self, tint: Color) -> Self {
+        Self { tint, ..self }
+    }
+    pub fn with_absorption(self, absorption: Absorption) -> Self {
+        Self { absorption, ..self }
+    }
+    /// RGB rendering keeps using the index at the d-line (587.6nm)
+    pub fn with_dispersion(self, dispersion: Dispersion) -> Self {
+        Self { ri: dispersion.ior(587.6).unwrap_or(self.ri), dispersion, ..self }
     }
     pub fn schlick(cosine: f64, ri: f64) -> f64 {
         let r0 = ((1.0 - ri) / (1.0 + ri)).powi(2);
@@ -262,32 +492,230 @@ impl Dielectric {
 
 impl Material for Dielectric {
     fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        //中を通ってきたなら、その距離の分だけ吸収する
+        let attenuation = self.absorption.transmittance(ray, hit);
+        let ri = self.dispersion.ior(ray.wavelength).unwrap_or(self.ri);
         let reflected = ray.d.reflect(hit.n);
         let (outward_normal, ni_over_nt, cosine) = {
             let dot = ray.d.dot(&hit.n);
             if dot > 0.0 {
-                (-hit.n , self.ri, self.ri * dot / ray.d.length().sqrt())
+                (-hit.n , ri, ri * dot / ray.d.length().sqrt())
             } else {
                 (
                     hit.n,
-                    1.0 / self.ri,
-                    -self.ri * dot / ray.d.length().sqrt() ,
+                    1.0 / ri,
+                    -ri * dot / ray.d.length().sqrt() ,
                 )
             }
         };
-        if let Some(refracted) = (-ray.d ).refract(outward_normal, ni_over_nt) {
-            if Vec3::random_full().x > Self::schlick(cosine, self.ri) {
-                return Some(ScatterInfo::new(
-                    Ray::new(hit.p, refracted),
-                    Vec3::new(1.0, 1.0, 1.0),
-                    None,
-                ));
-            }
+        if let Some(refracted) = (-ray.d ).refract(outward_normal, ni_over_nt)
+            && Vec3::random_full().x > Self::schlick(cosine, ri) {
+            return Some(ScatterInfo::new(
+                Ray::with_time(hit.p, refracted, ray.time),
+                attenuation.mult(self.tint),
+                None,
+            ));
         }
         Some(ScatterInfo::new(
-            Ray::new(hit.p, reflected),
-            Vec3::new(1.0, 1.0, 1.0),
+            Ray::with_time(hit.p, reflected, ray.time),
+            attenuation,
             None,
         ))
     }
+    fn is_dispersive(&self) -> bool {
+        !matches!(self.dispersion, Dispersion::None)
+    }
+}
+
+// GGXの粗いガラス(すりガラス)。roughnessはテクスチャのx成分を使う
+pub struct RoughDielectric {
+    pub ri: f64,
+    pub roughness: Box<dyn Texture>,
+    pub tint: Color,
+    pub absorption: Absorption,
+}
+
+impl RoughDielectric {
+    pub fn new(ri: f64, roughness: Box<dyn Texture>) -> Self {
+        Self { ri, roughness, tint: Color::full(1.0), absorption: Absorption::none() }
+    }
+    pub fn with_tint(self, tint: Color) -> Self {
+        Self { tint, ..self }
+    }
+    pub fn with_absorption(self, absorption: Absorption) -> Self {
+        Self { absorption, ..self }
+    }
+    fn ggx(&self, hit: &HitInfo) -> GGX {
+        GGX::from_roughness(self.roughness.value(hit.u, hit.v, hit.p).x, 0.0)
+    }
+    //外から入るならri、中から出るなら1/ri
+    fn eta(&self, ray: &Ray, hit: &HitInfo) -> f64 {
+        if ray.d.dot(&hit.n) < 0.0 { self.ri } else { 1.0 / self.ri }
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let ggx = self.ggx(hit);
+        let eta = self.eta(ray, hit);
+        let attenuation = self.absorption.transmittance(ray, hit);
+        if !ggx.is_smooth() {
+            return Some(ScatterInfo::new(*ray, attenuation, Some(Arc::new(GgxDielectricPdf::new(ray, hit, ggx, eta)))));
+        }
+        //十分滑らかなら正確なFresnelで鏡面反射か屈折
+        let (onb, wo) = shading_frame(ray, hit);
+        let n = Vec3::zaxis();
+        let (wi, albedo) = match refract_local(wo, n, eta) {
+            Some(t) if random() >= fresnel_dielectric(wo.z, eta) => (t, attenuation.mult(self.tint)),
+            _ => (n * 2.0 * wo.z - wo, attenuation),
+        };
+        Some(ScatterInfo::new(Ray::with_time(hit.p, onb.local(wi), ray.time), albedo, None))
+    }
+    fn bsdf(&self, ray: &Ray, hit: &HitInfo, _scatter: &ScatterInfo, scattered: &Ray) -> Color {
+        let (onb, wo) = shading_frame(ray, hit);
+        let wi = onb.to_local(scattered.d.norm());
+        let color = if wi.z < 0.0 { self.tint } else { Color::full(1.0) };
+        let attenuation = self.absorption.transmittance(ray, hit);
+        attenuation.mult(color) * ggx_dielectric(&self.ggx(hit), wo, wi, self.eta(ray, hit))
+    }
+}
+
+// 任意のマテリアルに誘電体の薄いコートを被せる(車の塗装、ニス、樹脂など)
+// コートの反射はFresnelの確率で選び、下地にはコートを2回通る分の(1-F)と吸収を掛ける
+// 下地はコート内での屈折を無視して外側の方向のまま評価する
+pub struct Coated {
+    pub base: Arc<dyn Material>,
+    pub ior: f64,
+    pub ggx: GGX,
+    pub thickness: f64,
+    //厚さ1を垂直に通った時の透過色
+    pub color: Color,
+}
+
+impl Coated {
+    pub fn new(base: Arc<dyn Material>, ior: f64, roughness: f64, thickness: f64, color: Color) -> Self {
+        Self { base, ior, ggx: GGX::from_roughness(roughness, 0.0), thickness, color }
+    }
+
+    //コートを往復する間の吸収。斜めに通るほど長くなる
+    fn transmittance(&self, cos_o: f64, cos_i: f64) -> Color {
+        if self.thickness <= 0.0 {
+            return Color::full(1.0);
+        }
+        let inside = |c: f64| (1.0 - (1.0 - c * c) / (self.ior * self.ior)).max(1e-8).sqrt();
+        let d = self.thickness * (1.0 / inside(cos_o) + 1.0 / inside(cos_i));
+        Color::new(self.color.x.powf(d), self.color.y.powf(d), self.color.z.powf(d))
+    }
+
+    /// Fraction of light that enters the coat, reaches the base and leaves again
+    pub fn base_weight(&self, cos_o: f64, cos_i: f64) -> Color {
+        let t = (1.0 - fresnel_dielectric(cos_o, self.ior)) * (1.0 - fresnel_dielectric(cos_i, self.ior));
+        self.transmittance(cos_o, cos_i) * t
+    }
+}
+
+// pdfを返す時のalbedoは、その枝を選んだ確率の逆数
+impl Material for Coated {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let (onb, wo) = shading_frame(ray, hit);
+        let f = fresnel_dielectric(wo.z, self.ior);
+        let smooth = self.ggx.is_smooth();
+        if smooth && random() < f {
+            let reflected = onb.local(Vec3::new(-wo.x, -wo.y, wo.z));
+            return Some(ScatterInfo::new(Ray::with_time(hit.p, reflected, ray.time), Color::full(1.0), None));
+        }
+        let coat = || -> Arc<dyn Pdf> { Arc::new(GgxPdf::new(ray, hit, self.ggx)) };
+        let Some(base) = self.base.scatter(ray, hit) else {
+            //下地が何も返さないなら粗いコートの反射だけ
+            return if smooth { None } else { Some(ScatterInfo::new(*ray, Color::full(1.0), Some(coat()))) };
+        };
+        //鏡面の下地。粗いコートとはFの確率で選ぶ
+        let Some(pdf) = base.pdf.clone() else {
+            if !smooth && random() < f {
+                return Some(ScatterInfo::new(*ray, Color::full(1.0 / f), Some(coat())));
+            }
+            let cos_i = onb.to_local(base.ray.d.norm()).z.abs();
+            let albedo = base.albedo.mult(self.base_weight(wo.z, cos_i)) / (1.0 - f);
+            return Some(ScatterInfo::new(base.ray, albedo, None));
+        };
+        let info = if smooth {
+            ScatterInfo::new(*ray, Color::full(1.0 / (1.0 - f)), Some(pdf))
+        } else {
+            ScatterInfo::new(*ray, Color::full(1.0), Some(Arc::new(MixturePdf::weighted(coat(), pdf, f))))
+        };
+        Some(info.with_base(base))
+    }
+    fn bsdf(&self, ray: &Ray, hit: &HitInfo, scatter: &ScatterInfo, scattered: &Ray) -> Color {
+        let (onb, wo) = shading_frame(ray, hit);
+        let wi = onb.to_local(scattered.d.norm());
+        let mut f = Color::zero();
+        if !self.ggx.is_smooth() {
+            f = ggx_reflection(&self.ggx, wo, wi, |c| Color::full(fresnel_dielectric(c, self.ior)));
+        }
+        if let Some(base) = &scatter.base {
+            f = f + self.base.bsdf(ray, hit, base, scattered).mult(self.base_weight(wo.z, wi.z.abs()));
+        }
+        f * scatter.albedo.x
+    }
+}
+
+//媒質内の等方性散乱(位相関数)
+pub struct Isotropic {
+    pub albedo: Box<dyn Texture>,
+    pub pdf: Arc<dyn Pdf>,
+}
+
+impl Isotropic {
+    pub fn new(albedo: Box<dyn Texture>) -> Self {
+        Self { albedo, pdf: Arc::new(SpherePdf::new()) }
+    }
+}
+
+impl Material for Isotropic {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let albedo = self.albedo.value(hit.u, hit.v, hit.p);
+        Some(ScatterInfo::new(*ray, albedo, Some(Arc::clone(&self.pdf))))
+    }
+    fn scattering_pdf(&self, _ray: &Ray, _hit: &HitInfo) -> f64 {
+        0.25 * FRAC_1_PI
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //呼ぶたびに違う色を返す下地
+    struct RandomBase {}
+
+    impl Material for RandomBase {
+        fn scatter(&self, ray: &Ray, _hit: &HitInfo) -> Option<ScatterInfo> {
+            Some(ScatterInfo::new(*ray, Color::full(random()), Some(Arc::new(CosinePdf::new()))))
+        }
+        fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo) -> f64 {
+            ray.d.norm().dot(&hit.n).max(0.0) * FRAC_1_PI
+        }
+    }
+
+    #[test]
+    fn coated_bsdf_uses_the_sampled_base() {
+        let coated = Coated::new(Arc::new(RandomBase {}), 1.5, 0.0, 0.0, Color::full(1.0));
+        let m: Arc<dyn Material> = Arc::new(RandomBase {});
+        let hit = HitInfo::new(1.0, Vec3::zero(), Vec3::zaxis(), m, 0.0, 0.0);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.3, 0.0, -1.0));
+        let scattered = Ray::new(Vec3::zero(), Vec3::new(-0.2, 0.4, 1.0));
+        let cos_o = -ray.d.norm().z;
+        let cos_i = scattered.d.norm().z;
+        for _ in 0..100 {
+            let Some(scatter) = coated.scatter(&ray, &hit) else { continue };
+            let Some(base) = &scatter.base else {
+                //コートの鏡面反射を選んだ時は下地を持たない
+                assert!(scatter.pdf.is_none());
+                continue;
+            };
+            let expected = base.albedo.x * cos_i * FRAC_1_PI * coated.base_weight(cos_o, cos_i).x * scatter.albedo.x;
+            let f = coated.bsdf(&ray, &hit, &scatter, &scattered);
+            assert!((f.x - expected).abs() < 1e-12, "{} != {}", f.x, expected);
+        }
+    }
 }
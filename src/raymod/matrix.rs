@@ -0,0 +1,524 @@
+use crate::raymod::*;
+
+use std::ops::Mul;
+use std::sync::Arc;
+
+/// A row-major 4x4 matrix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+    pub const fn new(m: [[f64; 4]; 4]) -> Self {
+        Self { m }
+    }
+
+    pub const fn identity() -> Self {
+        Self::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut t = [[0.0; 4]; 4];
+        for (i, row) in t.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = self.m[j][i];
+            }
+        }
+        Self::new(t)
+    }
+
+    /// Determinant of the upper 3x3 (linear) part
+    pub fn determinant3(&self) -> f64 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// Compute the inverse by Gauss-Jordan elimination, None if singular
+    pub fn inverse(&self) -> Option<Self> {
+        let mut a = self.m;
+        let mut inv = Self::identity().m;
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())?;
+            if a[pivot][col].abs() < f64::EPSILON {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let recip = a[col][col].recip();
+            for j in 0..4 {
+                a[col][j] *= recip;
+                inv[col][j] *= recip;
+            }
+            for row in 0..4 {
+                if row != col {
+                    let f = a[row][col];
+                    for j in 0..4 {
+                        a[row][j] -= f * a[col][j];
+                        inv[row][j] -= f * inv[col][j];
+                    }
+                }
+            }
+        }
+        Some(Self::new(inv))
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        let mut r = [[0.0; 4]; 4];
+        for (i, row) in r.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum();
+            }
+        }
+        Self::new(r)
+    }
+}
+
+/// An affine transform with its inverse
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    pub m: Matrix4,
+    pub inv: Matrix4,
+}
+
+impl Transform {
+    pub const fn identity() -> Self {
+        Self { m: Matrix4::identity(), inv: Matrix4::identity() }
+    }
+
+    /// Construct from a matrix, None if it is not invertible
+    pub fn from_matrix(m: Matrix4) -> Option<Self> {
+        m.inverse().map(|inv| Self { m, inv })
+    }
+
+    pub fn translate(v: Vec3) -> Self {
+        let mut m = Matrix4::identity();
+        let mut inv = Matrix4::identity();
+        for a in 0..3 {
+            m.m[a][3] = v[a];
+            inv.m[a][3] = -v[a];
+        }
+        Self { m, inv }
+    }
+
+    pub fn scale(s: Vec3) -> Self {
+        let mut m = Matrix4::identity();
+        let mut inv = Matrix4::identity();
+        for a in 0..3 {
+            m.m[a][a] = s[a];
+            inv.m[a][a] = s[a].recip();
+        }
+        Self { m, inv }
+    }
+
+    /// Rotation about a unit axis in degrees
+    pub fn rotate(axis: Vec3, angle: f64) -> Self {
+        Self::from_quat(&Quat::from_rot(axis, angle.to_radians()))
+    }
+
+    pub fn from_quat(q: &Quat) -> Self {
+        let m = q.to_matrix();
+        //回転行列の逆は転置
+        Self { m, inv: m.transpose() }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self { m: self.inv, inv: self.m }
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        let m = &self.m.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1.0 { Vec3::new(x, y, z) } else { Vec3::new(x, y, z) / w }
+    }
+
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Transform a normal by the inverse transpose (not normalized)
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        let inv = &self.inv.m;
+        Vec3::new(
+            inv[0][0] * n.x + inv[1][0] * n.y + inv[2][0] * n.z,
+            inv[0][1] * n.x + inv[1][1] * n.y + inv[2][1] * n.z,
+            inv[0][2] * n.x + inv[1][2] * n.y + inv[2][2] * n.z,
+        )
+    }
+
+    //方向は正規化しないのでtはそのまま使える
+    pub fn ray(&self, r: &Ray) -> Ray {
+        Ray::with_time(self.point(r.o), self.vector(r.d), r.time).with_wavelength(r.wavelength)
+    }
+
+    pub fn aabb(&self, b: &AABB) -> AABB {
+        let mut min = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = Vec3::new(-f64::INFINITY, -f64::INFINITY, -f64::INFINITY);
+        for i in 0..8 {
+            let corner = Vec3::new(
+                if i & 1 == 0 { b.min.x } else { b.max.x },
+                if i & 2 == 0 { b.min.y } else { b.max.y },
+                if i & 4 == 0 { b.min.z } else { b.max.z },
+            );
+            let p = self.point(corner);
+            for a in 0..3 {
+                min[a] = min[a].min(p[a]);
+                max[a] = max[a].max(p[a]);
+            }
+        }
+        AABB { min, max }
+    }
+
+    /// Light pdf of `shape` placed by this transform.
+    /// The local solid-angle density is converted with the Jacobian of the
+    /// direction mapping, |det A^-1| / |A^-1 w|^3 for a unit direction w.
+    pub fn shape_pdf(&self, shape: &dyn Shape, o: Vec3, v: Vec3, time: f64) -> f64 {
+        let inv = self.inverse();
+        let local_v = inv.vector(v);
+        let stretch = (v.length() / local_v.length()).sqrt();
+        shape.pdf_value(inv.point(o), local_v, time) * self.inv.determinant3().abs() * stretch.powi(3)
+    }
+
+    pub fn shape_random(&self, shape: &dyn Shape, o: Vec3, time: f64) -> Vec3 {
+        self.vector(shape.random(self.inverse().point(o), time))
+    }
+
+    //一様スケールなら正確、それ以外は体積比からの近似(光源を選ぶ重みにしか使わない)
+    pub fn shape_area(&self, shape: &dyn Shape) -> f64 {
+        shape.area() * self.m.determinant3().abs().powf(2.0 / 3.0)
+    }
+}
+
+/// `a * b` applies `b` first, then `a`
+impl Mul for Transform {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self { m: self.m * rhs.m, inv: rhs.inv * self.inv }
+    }
+}
+
+// 任意のアフィン変換をかけた形状
+pub struct Transformed {
+    pub shape: Box<dyn Shape>,
+    pub transform: Transform,
+}
+
+impl Transformed {
+    pub fn new(shape: Box<dyn Shape>, transform: Transform) -> Self {
+        Self { shape, transform }
+    }
+
+    pub fn translate(shape: Box<dyn Shape>, offset: Vec3) -> Self {
+        Self::new(shape, Transform::translate(offset))
+    }
+
+    /// Rotation about a unit axis in degrees
+    pub fn rotate(shape: Box<dyn Shape>, axis: Vec3, angle: f64) -> Self {
+        Self::new(shape, Transform::rotate(axis, angle))
+    }
+}
+
+impl Shape for Transformed {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let local_ray = self.transform.inverse().ray(ray);
+        let hit = self.shape.hit(&local_ray, t0, t1)?;
+        Some(HitInfo { p: self.transform.point(hit.p), n: self.transform.normal(hit.n).norm(), ..hit })
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        self.shape.bounding_box().map(|b| self.transform.aabb(&b))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.transform.shape_pdf(self.shape.as_ref(), o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.transform.shape_random(self.shape.as_ref(), o, time)
+    }
+    fn is_emissive(&self) -> bool {
+        self.shape.is_emissive()
+    }
+    fn emitters(&self) -> Vec<Arc<dyn Shape>> {
+        self.shape
+            .emitters()
+            .into_iter()
+            .map(|e| Arc::new(Transformed::new(Box::new(e), self.transform)) as Arc<dyn Shape>)
+            .collect()
+    }
+    fn area(&self) -> f64 {
+        self.transform.shape_area(self.shape.as_ref())
+    }
+    fn emission(&self) -> Color {
+        self.shape.emission()
+    }
+}
+
+// キーフレーム(時刻, 値)。時刻順に並べておく
+#[derive(Clone)]
+pub enum Motion {
+    Translate(Vec<(f64, Vec3)>),
+    //軸と角度(度)。角度を直接補間するので一周以上も回せる
+    Rotate(Vec3, Vec<(f64, f64)>),
+}
+
+//範囲外の時刻は端のキーに固定
+fn keyframe_lerp<T: Copy>(keys: &[(f64, T)], time: f64, lerp: impl Fn(T, T, f64) -> T) -> T {
+    let i = keys.partition_point(|k| k.0 <= time);
+    if i == 0 {
+        return keys[0].1;
+    }
+    if i == keys.len() {
+        return keys[i - 1].1;
+    }
+    let (k0, k1) = (keys[i - 1], keys[i]);
+    lerp(k0.1, k1.1, (time - k0.0) / (k1.0 - k0.0))
+}
+
+impl Motion {
+    pub fn at(&self, time: f64) -> Transform {
+        match self {
+            Motion::Translate(keys) => Transform::translate(keyframe_lerp(keys, time, |a, b, s| a + (b - a) * s)),
+            Motion::Rotate(axis, keys) => {
+                Transform::rotate(*axis, keyframe_lerp(keys, time, |a, b, s| a + (b - a) * s))
+            }
+        }
+    }
+
+    /// Bounding box of `b` over the whole motion
+    pub fn aabb(&self, b: &AABB) -> AABB {
+        match self {
+            //平行移動は各キーの箱を合わせれば十分
+            Motion::Translate(keys) => keys
+                .iter()
+                .map(|k| Transform::translate(k.1).aabb(b))
+                .reduce(|acc, x| surrounding_box(&acc, &x))
+                .unwrap(),
+            Motion::Rotate(axis, keys) => {
+                //角度を細かく刻んで合わせ、弦と弧のずれ(矢高)の分だけ広げる
+                const STEP: f64 = 5.0;
+                let mut bbox = Transform::rotate(*axis, keys[0].1).aabb(b);
+                let mut max_step: f64 = 0.0;
+                for w in keys.windows(2) {
+                    let (a0, a1) = (w[0].1, w[1].1);
+                    let n = ((a1 - a0).abs() / STEP).ceil().max(1.0);
+                    max_step = max_step.max((a1 - a0).abs() / n);
+                    for i in 1..=n as usize {
+                        let angle = a0 + (a1 - a0) * i as f64 / n;
+                        bbox = surrounding_box(&bbox, &Transform::rotate(*axis, angle).aabb(b));
+                    }
+                }
+                let radius = (0..8)
+                    .map(|i| {
+                        Vec3::new(
+                            if i & 1 == 0 { b.min.x } else { b.max.x },
+                            if i & 2 == 0 { b.min.y } else { b.max.y },
+                            if i & 4 == 0 { b.min.z } else { b.max.z },
+                        )
+                        .length()
+                    })
+                    .fold(0.0, f64::max)
+                    .sqrt();
+                let pad = Vec3::full(radius * (1.0 - (max_step.to_radians() * 0.5).cos()));
+                AABB::new(bbox.min - pad, bbox.max + pad)
+            }
+        }
+    }
+}
+
+// キーフレームで動く形状。ray.timeでの変換をかけて判定する
+pub struct Animated {
+    pub shape: Box<dyn Shape>,
+    pub motion: Motion,
+}
+
+impl Animated {
+    /// Keys are sorted by time here
+    ///
+    /// # Panics
+    ///
+    /// Panics if `motion` has no keys.
+    pub fn new(shape: Box<dyn Shape>, mut motion: Motion) -> Self {
+        let len = match &mut motion {
+            Motion::Translate(keys) => {
+                keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+                keys.len()
+            }
+            Motion::Rotate(_, keys) => {
+                keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+                keys.len()
+            }
+        };
+        assert!(len > 0, "Animated needs at least one keyframe");
+        Self { shape, motion }
+    }
+
+    /// Keyframed translation: (time, offset) pairs, linearly interpolated
+    pub fn translate(shape: Box<dyn Shape>, keys: Vec<(f64, Vec3)>) -> Self {
+        Self::new(shape, Motion::Translate(keys))
+    }
+
+    /// Keyframed rotation about a unit axis: (time, degrees) pairs, linearly interpolated
+    pub fn rotate(shape: Box<dyn Shape>, axis: Vec3, keys: Vec<(f64, f64)>) -> Self {
+        Self::new(shape, Motion::Rotate(axis, keys))
+    }
+}
+
+impl Shape for Animated {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let transform = self.motion.at(ray.time);
+        let local_ray = transform.inverse().ray(ray);
+        let hit = self.shape.hit(&local_ray, t0, t1)?;
+        Some(HitInfo { p: transform.point(hit.p), n: transform.normal(hit.n).norm(), ..hit })
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        self.shape.bounding_box().map(|b| self.motion.aabb(&b))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.motion.at(time).shape_pdf(self.shape.as_ref(), o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.motion.at(time).shape_random(self.shape.as_ref(), o, time)
+    }
+    fn is_emissive(&self) -> bool {
+        self.shape.is_emissive()
+    }
+    fn emitters(&self) -> Vec<Arc<dyn Shape>> {
+        self.shape
+            .emitters()
+            .into_iter()
+            .map(|e| Arc::new(Animated { shape: Box::new(e), motion: self.motion.clone() }) as Arc<dyn Shape>)
+            .collect()
+    }
+    //平行移動と回転だけなので面積は変わらない
+    fn area(&self) -> f64 {
+        self.shape.area()
+    }
+    fn emission(&self) -> Color {
+        self.shape.emission()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Matrix4, b: Matrix4) {
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((a.m[i][j] - b.m[i][j]).abs() < 1e-12, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    // 回転・非一様スケール・せん断・平行移動を含む変換
+    fn affine() -> Matrix4 {
+        let shear = Matrix4::new([
+            [1.0, 0.4, 0.0, 0.0],
+            [0.0, 1.0, -0.3, 0.0],
+            [0.2, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let t = Transform::translate(Vec3::new(1.0, -2.0, 3.0))
+            * Transform::rotate(Vec3::new(1.0, 2.0, 2.0).norm(), 37.0)
+            * Transform::scale(Vec3::new(2.0, 0.5, 1.5));
+        t.m * shear
+    }
+
+    #[test]
+    fn inverse_times_matrix_is_identity() {
+        let m = affine();
+        let inv = m.inverse().unwrap();
+        assert_near(inv * m, Matrix4::identity());
+        assert_near(m * inv, Matrix4::identity());
+        assert!((m.determinant3() * inv.determinant3() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        let mut m = Matrix4::identity();
+        m.m[1] = [2.0, 0.0, 0.0, 1.0];
+        m.m[0] = [1.0, 0.0, 0.0, 0.5];
+        assert!(m.inverse().is_none());
+        assert!(Transform::from_matrix(m).is_none());
+    }
+
+    #[test]
+    fn composed_transform_and_inverse() {
+        let a = Transform::from_matrix(affine()).unwrap();
+        let b = Transform::rotate(Vec3::zaxis(), 90.0) * Transform::translate(Vec3::new(0.0, 1.0, 0.0));
+        let p = Vec3::new(0.3, -1.2, 2.5);
+        let q = (a * b).point(p);
+        let expected = a.point(b.point(p));
+        assert!((q - expected).length() < 1e-20);
+        assert!(((a * b).inverse().point(q) - p).length() < 1e-20);
+    }
+
+    fn material() -> Arc<dyn Material> {
+        Arc::new(DiffuseLight::new(Box::new(ColorTexture::new(Vec3::full(1.0)))))
+    }
+
+    // 同じ形を直接置いた形状と比べ、方向ごとのpdfと1/pdfの平均(=立体角)が一致すること
+    fn assert_same_light(transformed: &dyn Shape, direct: &dyn Shape, o: Vec3) {
+        for _ in 0..200 {
+            let v = direct.random(o, 0.0);
+            let expected = direct.pdf_value(o, v, 0.0);
+            let actual = transformed.pdf_value(o, v * 3.0, 0.0);
+            assert!((actual - expected).abs() < 1e-9 * expected, "{} != {}", actual, expected);
+        }
+        let n = 20000;
+        let solid_angle = |s: &dyn Shape| (0..n).map(|_| s.pdf_value(o, s.random(o, 0.0), 0.0).recip()).sum::<f64>() / n as f64;
+        let (a, b) = (solid_angle(transformed), solid_angle(direct));
+        assert!((a - b).abs() < 0.03 * b, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn transformed_rect_pdf_under_non_uniform_scale() {
+        let unit = Rect::new(0.0, 1.0, 0.0, 1.0, 0.0, RectAxisType::XY, material());
+        let scaled = Transformed::new(Box::new(unit), Transform::scale(Vec3::new(2.0, 1.0, 1.0)));
+        let wide = Rect::new(0.0, 2.0, 0.0, 1.0, 0.0, RectAxisType::XY, material());
+        assert_same_light(&scaled, &wide, Vec3::new(0.7, 0.3, 3.0));
+        assert_same_light(&scaled, &wide, Vec3::new(-2.0, 1.5, -1.0));
+    }
+
+    #[test]
+    fn transformed_triangle_pdf_under_shear() {
+        let m = Transform::from_matrix(affine()).unwrap();
+        let (p0, p1, p2) = (Vec3::zero(), Vec3::xaxis(), Vec3::new(0.2, 1.0, 0.3));
+        let local = Triangle::from_points(p0, p1, p2, material());
+        let transformed = Transformed::new(Box::new(local), m);
+        let direct = Triangle::from_points(m.point(p0), m.point(p1), m.point(p2), material());
+        assert_same_light(&transformed, &direct, Vec3::new(4.0, 2.0, -3.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Animated needs at least one keyframe")]
+    fn animated_without_keys_panics() {
+        let rect = Rect::new(0.0, 1.0, 0.0, 1.0, 0.0, RectAxisType::XY, material());
+        Animated::translate(Box::new(rect), Vec::new());
+    }
+
+    #[test]
+    fn animated_with_one_key_stays_put() {
+        let rect = Rect::new(0.0, 1.0, 0.0, 1.0, 0.0, RectAxisType::XY, material());
+        let animated = Animated::rotate(Box::new(rect), Vec3::zaxis(), vec![(0.5, 90.0)]);
+        let bbox = animated.bounding_box().unwrap();
+        assert!((bbox.min.x + 1.0).abs() < 1e-9 && bbox.max.y > 1.0 - 1e-9);
+        let ray = Ray::with_time(Vec3::new(-0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 2.0);
+        assert!(animated.hit(&ray, 0.0, f64::MAX).is_some());
+    }
+}
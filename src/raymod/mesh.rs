@@ -0,0 +1,159 @@
+use crate::raymod::*;
+use std::sync::Arc;
+
+// 頂点・法線・UVのインデックス。法線とUVは無い場合もある
+#[derive(Clone, Copy, Debug)]
+pub struct MeshFace {
+    pub v: [usize; 3],
+    pub n: Option<[usize; 3]>,
+    pub uv: Option<[usize; 3]>,
+}
+
+impl MeshFace {
+    pub const fn new(v: [usize; 3], n: Option<[usize; 3]>, uv: Option<[usize; 3]>) -> Self {
+        Self { v, n, uv }
+    }
+}
+
+//頂点・法線・UVの配列を全三角形で共有する
+pub struct TriangleMesh {
+    pub vertices: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f64, f64)>,
+    pub faces: Vec<MeshFace>,
+}
+
+impl TriangleMesh {
+    pub fn new(vertices: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(f64, f64)>, faces: Vec<MeshFace>) -> Self {
+        Self { vertices, normals, uvs, faces }
+    }
+}
+
+pub struct Triangle {
+    pub mesh: Arc<TriangleMesh>,
+    pub face: usize,
+    pub material: Arc<dyn Material>,
+}
+
+impl Triangle {
+    pub fn new(mesh: Arc<TriangleMesh>, face: usize, material: Arc<dyn Material>) -> Self {
+        Self { mesh, face, material }
+    }
+
+    /// Construct a stand-alone triangle from three points
+    pub fn from_points(p0: Vec3, p1: Vec3, p2: Vec3, material: Arc<dyn Material>) -> Self {
+        let face = MeshFace::new([0, 1, 2], None, None);
+        let mesh = TriangleMesh::new(vec![p0, p1, p2], Vec::new(), Vec::new(), vec![face]);
+        Self::new(Arc::new(mesh), 0, material)
+    }
+
+    fn points(&self) -> (Vec3, Vec3, Vec3) {
+        let [i0, i1, i2] = self.mesh.faces[self.face].v;
+        (self.mesh.vertices[i0], self.mesh.vertices[i1], self.mesh.vertices[i2])
+    }
+
+    /// Geometric normal following the winding order
+    pub fn geometric_normal(&self) -> Vec3 {
+        let (p0, p1, p2) = self.points();
+        ((p1 - p0) % (p2 - p0)).norm()
+    }
+}
+
+impl Shape for Triangle {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        // Moller-Trumbore
+        let (p0, p1, p2) = self.points();
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let pvec = ray.d % e2;
+        let det = e1.dot(&pvec);
+        if det.abs() < f64::EPSILON {
+            return None;
+        }
+        let inv_det = det.recip();
+        let tvec = ray.o - p0;
+        let b1 = tvec.dot(&pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = tvec % e1;
+        let b2 = ray.d.dot(&qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = e2.dot(&qvec) * inv_det;
+        if t < t0 || t > t1 {
+            return None;
+        }
+        let b0 = 1.0 - b1 - b2;
+
+        let face = &self.mesh.faces[self.face];
+        //シェーディング法線は頂点法線を補間、無ければ幾何法線
+        let n = match face.n {
+            Some([i0, i1, i2]) => {
+                let ns = self.mesh.normals[i0] * b0 + self.mesh.normals[i1] * b1 + self.mesh.normals[i2] * b2;
+                if ns.length() > 0.0 { ns.norm() } else { (e1 % e2).norm() }
+            }
+            None => (e1 % e2).norm(),
+        };
+        let (u, v) = match face.uv {
+            Some([i0, i1, i2]) => {
+                let (uv0, uv1, uv2) = (self.mesh.uvs[i0], self.mesh.uvs[i1], self.mesh.uvs[i2]);
+                (
+                    uv0.0 * b0 + uv1.0 * b1 + uv2.0 * b2,
+                    uv0.1 * b0 + uv1.1 * b1 + uv2.1 * b2,
+                )
+            }
+            None => (b1, b2),
+        };
+        Some(HitInfo::new(t, ray.at(t), n, Arc::clone(&self.material), u, v))
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        let (p0, p1, p2) = self.points();
+        let mut min = Vec3::zero();
+        let mut max = Vec3::zero();
+        for a in 0..3 {
+            min[a] = p0[a].min(p1[a]).min(p2[a]);
+            max[a] = p0[a].max(p1[a]).max(p2[a]);
+            //軸に平行な三角形は厚みを持たせる
+            if max[a] - min[a] < EPS10 {
+                min[a] -= EPS10;
+                max[a] += EPS10;
+            }
+        }
+        Some(AABB { min, max })
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3, _time: f64) -> f64 {
+        if let Some(hit) = self.hit(&Ray::new(o, v), EPS10, f64::MAX) {
+            let distance_squared = hit.t.powi(2) * v.length();
+            let cosine = v.dot(&self.geometric_normal()).abs() / v.length().sqrt();
+            distance_squared / (cosine * self.area())
+        } else {
+            0.0
+        }
+    }
+
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
+        let (p0, p1, p2) = self.points();
+        let s = random().sqrt();
+        let r = random();
+        let b0 = 1.0 - s;
+        let b1 = r * s;
+        p0 * b0 + p1 * b1 + p2 * (1.0 - b0 - b1) - o
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn area(&self) -> f64 {
+        let (p0, p1, p2) = self.points();
+        ((p1 - p0) % (p2 - p0)).length().sqrt() * 0.5
+    }
+
+    fn emission(&self) -> Color {
+        self.material.emission()
+    }
+}
@@ -0,0 +1,251 @@
+use crate::raymod::*;
+
+// GGX(Trowbridge-Reitz)のマイクロファセット分布。ベクトルは法線が+zの局所座標
+#[derive(Copy, Clone, Debug)]
+pub struct GGX {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl GGX {
+    pub const fn new(alpha_x: f64, alpha_y: f64) -> Self {
+        Self { alpha_x, alpha_y }
+    }
+
+    /// Disney-style parameters: alpha = roughness^2, stretched along u by `anisotropic` in [0,1)
+    pub fn from_roughness(roughness: f64, anisotropic: f64) -> Self {
+        let aspect = (1.0 - 0.9 * anisotropic.clamp(0.0, 1.0)).sqrt();
+        let alpha = roughness * roughness;
+        Self::new((alpha / aspect).max(1e-4), (alpha * aspect).max(1e-4))
+    }
+
+    //これより滑らかなら完全鏡面として扱う
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+
+    /// Normal distribution D(h)
+    pub fn d(&self, h: Vec3) -> f64 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let e = (h.x / self.alpha_x).powi(2) + (h.y / self.alpha_y).powi(2) + h.z * h.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    //Smithのマスキング関数のΛ
+    fn lambda(&self, w: Vec3) -> f64 {
+        if w.z == 0.0 {
+            return f64::INFINITY;
+        }
+        let a2 = ((self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2)) / (w.z * w.z);
+        (-1.0 + (1.0 + a2).sqrt()) * 0.5
+    }
+
+    pub fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Height-correlated masking-shadowing
+    pub fn g2(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Sample a microfacet normal visible from `wo` (Heitz 2018)
+    pub fn sample_visible(&self, wo: Vec3) -> Vec3 {
+        //半球に引き伸ばして見える法線を選び、元に戻す
+        let vh = Vec3::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).norm();
+        let lensq = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if lensq > 0.0 { Vec3::new(-vh.y, vh.x, 0.0) / lensq.sqrt() } else { Vec3::xaxis() };
+        let t2 = vh % t1;
+        let r = random().sqrt();
+        let (sin_phi, cos_phi) = (2.0 * PI * random()).sin_cos();
+        let p1 = r * cos_phi;
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * sin_phi;
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+        Vec3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).norm()
+    }
+
+    /// Solid angle pdf of reflecting `wo` into `wi` with visible-normal sampling
+    pub fn reflection_pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).norm();
+        self.g1(wo) * self.d(h) / (4.0 * wo.z)
+    }
+}
+
+/// Fresnel reflectance of a conductor with complex index `eta + i k` (per channel)
+pub fn fresnel_conductor(cos_i: f64, eta: Color, k: Color) -> Color {
+    let f = |eta: f64, k: f64| {
+        let cos2 = cos_i * cos_i;
+        let sin2 = 1.0 - cos2;
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * cos_i * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rp + rs)
+    };
+    Color::new(f(eta.x, k.x), f(eta.y, k.y), f(eta.z, k.z))
+}
+
+/// Schlick's approximation with a coloured reflectance at normal incidence
+pub fn fresnel_schlick(cos_i: f64, f0: Color) -> Color {
+    f0 + (Color::full(1.0) - f0) * (1.0 - cos_i).max(0.0).powi(5)
+}
+
+/// Exact Fresnel reflectance of a dielectric; `eta` is the transmitted over the incident index
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+// 法線hの面でwoを屈折させる。全反射ならNone
+pub fn refract_local(wo: Vec3, h: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_o = wo.dot(&h);
+    let sin2_t = (1.0 - cos_o * cos_o).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + (cos_o / eta - cos_t) * h)
+}
+
+// 金属の複素屈折率(R, G, B の代表波長)
+pub fn conductor_preset(name: &str) -> Option<(Color, Color)> {
+    let (eta, k) = match name {
+        "gold" => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+        "silver" => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+        "copper" => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+        "aluminium" | "aluminum" => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+        "iron" => ([2.911, 2.950, 2.585], [3.089, 2.932, 2.767]),
+        _ => return None,
+    };
+    Some((Color::new(eta[0], eta[1], eta[2]), Color::new(k[0], k[1], k[2])))
+}
+
+// 表面の局所座標。法線は入射側に向ける
+pub fn shading_frame(ray: &Ray, hit: &HitInfo) -> (ONB, Vec3) {
+    let n = if ray.d.dot(&hit.n) > 0.0 { -hit.n } else { hit.n };
+    let onb = ONB::new(n);
+    let wo = onb.to_local(-ray.d.norm());
+    (onb, wo)
+}
+
+// 見える法線の分布から反射方向を選ぶ
+pub struct GgxPdf {
+    pub onb: ONB,
+    pub wo: Vec3,
+    pub ggx: GGX,
+}
+
+impl GgxPdf {
+    pub fn new(ray: &Ray, hit: &HitInfo, ggx: GGX) -> Self {
+        let (onb, wo) = shading_frame(ray, hit);
+        Self { onb, wo, ggx }
+    }
+}
+
+impl Pdf for GgxPdf {
+    fn value(&self, _hit: &HitInfo, direction: Vec3) -> f64 {
+        self.ggx.reflection_pdf(self.wo, self.onb.to_local(direction.norm()))
+    }
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        let h = self.ggx.sample_visible(self.wo);
+        self.onb.local(h * 2.0 * self.wo.dot(&h) - self.wo)
+    }
+}
+
+/// Microfacet reflection times cosine: D G F / (4 cos_o)
+pub fn ggx_reflection(ggx: &GGX, wo: Vec3, wi: Vec3, fresnel: impl Fn(f64) -> Color) -> Color {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return Color::zero();
+    }
+    let h = (wo + wi).norm();
+    fresnel(wo.dot(&h)) * (ggx.d(h) * ggx.g2(wo, wi) / (4.0 * wo.z))
+}
+
+// 透過の一般化ハーフベクトル。woの側(+z)に向ける
+fn transmission_half(wo: Vec3, wi: Vec3, eta: f64) -> Option<Vec3> {
+    let h = (wo + wi * eta).norm();
+    let h = if h.z < 0.0 { -h } else { h };
+    if wo.dot(&h) <= 0.0 || wi.dot(&h) >= 0.0 { None } else { Some(h) }
+}
+
+/// Rough dielectric BSDF times cosine (Walter et al. 2007); `wo` is on the +z side.
+/// Transmission is not scaled by 1/eta^2, matching the smooth `Dielectric`.
+pub fn ggx_dielectric(ggx: &GGX, wo: Vec3, wi: Vec3, eta: f64) -> f64 {
+    if wo.z <= 0.0 || wi.z == 0.0 {
+        return 0.0;
+    }
+    if wi.z > 0.0 {
+        let h = (wo + wi).norm();
+        return fresnel_dielectric(wo.dot(&h), eta) * ggx.d(h) * ggx.g2(wo, wi) / (4.0 * wo.z);
+    }
+    let Some(h) = transmission_half(wo, wi, eta) else { return 0.0 };
+    let (cos_o, cos_i) = (wo.dot(&h), wi.dot(&h));
+    let denom = cos_o + eta * cos_i;
+    (1.0 - fresnel_dielectric(cos_o, eta)) * ggx.d(h) * ggx.g2(wo, -wi) * eta * eta * cos_o * -cos_i
+        / (wo.z * denom * denom)
+}
+
+// 反射と屈折をFresnelの比で選ぶ
+pub struct GgxDielectricPdf {
+    pub onb: ONB,
+    pub wo: Vec3,
+    pub ggx: GGX,
+    pub eta: f64,
+}
+
+impl GgxDielectricPdf {
+    pub fn new(ray: &Ray, hit: &HitInfo, ggx: GGX, eta: f64) -> Self {
+        let (onb, wo) = shading_frame(ray, hit);
+        Self { onb, wo, ggx, eta }
+    }
+}
+
+impl Pdf for GgxDielectricPdf {
+    // 粗いと屈折した方向が入射側に来ることもあるので、半球で分けずに両方の密度を足す
+    fn value(&self, _hit: &HitInfo, direction: Vec3) -> f64 {
+        let (wo, wi) = (self.wo, self.onb.to_local(direction.norm()));
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        let mut pdf = 0.0;
+        let h = (wo + wi).norm();
+        if wo.dot(&h) > 0.0 {
+            pdf += fresnel_dielectric(wo.dot(&h), self.eta) * self.ggx.g1(wo) * self.ggx.d(h) / (4.0 * wo.z);
+        }
+        if let Some(h) = transmission_half(wo, wi, self.eta) {
+            let (cos_o, cos_i) = (wo.dot(&h), wi.dot(&h));
+            let denom = cos_o + self.eta * cos_i;
+            //見える法線の密度 × ハーフベクトルから方向へのヤコビアン
+            let visible = self.ggx.g1(wo) * cos_o * self.ggx.d(h) / wo.z;
+            pdf += (1.0 - fresnel_dielectric(cos_o, self.eta)) * visible * self.eta * self.eta * -cos_i / (denom * denom);
+        }
+        pdf
+    }
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        let h = self.ggx.sample_visible(self.wo);
+        let f = fresnel_dielectric(self.wo.dot(&h), self.eta);
+        let wi = match refract_local(self.wo, h, self.eta) {
+            Some(t) if random() >= f => t,
+            _ => h * 2.0 * self.wo.dot(&h) - self.wo,
+        };
+        self.onb.local(wi)
+    }
+}
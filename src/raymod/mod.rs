@@ -1,16 +1,40 @@
 mod bvh;
+mod envmap;
+mod instance;
+mod integrator;
+mod lights;
 mod material;
+mod matrix;
+mod mesh;
+mod microfacet;
+mod objload;
 mod optarg;
+mod principled;
 mod rayunit;
 mod scene;
+mod scenefile;
+mod sky;
+mod spectrum;
 mod vec3;
 mod quat;
 
 pub use self::bvh::*;
+pub use self::envmap::*;
+pub use self::instance::*;
+pub use self::integrator::*;
+pub use self::lights::*;
 pub use self::material::*;
+pub use self::matrix::*;
+pub use self::mesh::*;
+pub use self::microfacet::*;
+pub use self::objload::*;
 pub use self::optarg::*;
+pub use self::principled::*;
 pub use self::rayunit::*;
 pub use self::scene::*;
+pub use self::scenefile::*;
+pub use self::sky::*;
+pub use self::spectrum::*;
 pub use self::vec3::*;
 pub use self::quat::*;
 
@@ -0,0 +1,382 @@
+use crate::raymod::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// OBJ/MTL読み込み時のエラー。lineが0の場合はファイル単位のエラー
+#[derive(Debug)]
+pub struct ObjError {
+    pub path: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl ObjError {
+    fn new(path: &Path, line: usize, message: impl Into<String>) -> Self {
+        Self { path: path.display().to_string(), line, message: message.into() }
+    }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{}:{}: {}", self.path, self.line, self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+/// Load a Wavefront OBJ file (and its MTL libraries) into a BVH of triangles.
+/// Faces without `usemtl` get `default_material`.
+pub fn load_obj(path: &str, default_material: Arc<dyn Material>, bvh: &BVHBuilder) -> Result<BVH, ObjError> {
+    let path = Path::new(path);
+    let text = fs::read_to_string(path).map_err(|e| ObjError::new(path, 0, e.to_string()))?;
+
+    let mut vertices: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<(f64, f64)> = Vec::new();
+    let mut faces: Vec<MeshFace> = Vec::new();
+    let mut face_materials: Vec<Arc<dyn Material>> = Vec::new();
+    let mut library: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    let mut current = Arc::clone(&default_material);
+
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let err = |msg: String| ObjError::new(path, line, msg);
+        let mut tokens = raw.split('#').next().unwrap_or("").split_whitespace();
+        let Some(keyword) = tokens.next() else { continue };
+        let args: Vec<&str> = tokens.collect();
+        match keyword {
+            "v" => vertices.push(parse_vec3(&args).map_err(err)?),
+            "vn" => normals.push(parse_vec3(&args).map_err(err)?),
+            "vt" => {
+                if args.is_empty() {
+                    return Err(err("vt needs at least 1 value".to_string()));
+                }
+                let u = parse_f64(args[0]).map_err(err)?;
+                let v = if args.len() > 1 { parse_f64(args[1]).map_err(err)? } else { 0.0 };
+                uvs.push((u, v));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err(format!("face needs at least 3 vertices, got {}", args.len())));
+                }
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in &args {
+                    corners.push(
+                        parse_corner(arg, vertices.len(), uvs.len(), normals.len()).map_err(err)?,
+                    );
+                }
+                // 多角形は扇形に三角形分割
+                for k in 1..corners.len() - 1 {
+                    let (a, b, c) = (corners[0], corners[k], corners[k + 1]);
+                    let n = match (a.2, b.2, c.2) {
+                        (Some(na), Some(nb), Some(nc)) => Some([na, nb, nc]),
+                        _ => None,
+                    };
+                    let uv = match (a.1, b.1, c.1) {
+                        (Some(ta), Some(tb), Some(tc)) => Some([ta, tb, tc]),
+                        _ => None,
+                    };
+                    faces.push(MeshFace::new([a.0, b.0, c.0], n, uv));
+                    face_materials.push(Arc::clone(&current));
+                }
+            }
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err(err("mtllib needs a file name".to_string()));
+                }
+                for name in &args {
+                    library.extend(load_mtl(&sibling(path, name))?);
+                }
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                current = match library.get(&name) {
+                    Some(m) => Arc::clone(m),
+                    None => return Err(err(format!("undefined material '{}'", name))),
+                };
+            }
+            // グループ・スムージング等は無視
+            "o" | "g" | "s" | "l" | "p" => {}
+            _ => {}
+        }
+    }
+
+    if faces.is_empty() {
+        return Err(ObjError::new(path, 0, "no faces in file"));
+    }
+    let mesh = Arc::new(TriangleMesh::new(vertices, normals, uvs, faces));
+    let shapes: Vec<Box<dyn Shape>> = face_materials
+        .into_iter()
+        .enumerate()
+        .map(|(i, m)| Box::new(Triangle::new(Arc::clone(&mesh), i, m)) as Box<dyn Shape>)
+        .collect();
+    Ok(bvh.build(shapes))
+}
+
+// MTLの1マテリアル分の記述
+struct MtlEntry {
+    kd: Color,
+    ks: Color,
+    ke: Color,
+    ni: f64,
+    ns: f64,
+    dissolve: f64,
+    illum: i64,
+    map_kd: Option<PathBuf>,
+}
+
+impl MtlEntry {
+    fn new() -> Self {
+        Self {
+            kd: Color::new(0.8, 0.8, 0.8),
+            ks: Color::zero(),
+            ke: Color::zero(),
+            ni: 1.5,
+            ns: 0.0,
+            dissolve: 1.0,
+            illum: 2,
+            map_kd: None,
+        }
+    }
+
+    // 既存のマテリアルに対応付ける
+    fn build(&self, path: &Path) -> Result<Arc<dyn Material>, ObjError> {
+        let max = |c: Color| c.x.max(c.y).max(c.z);
+        if max(self.ke) > 0.0 {
+            return Ok(Arc::new(DiffuseLight::new(Box::new(ColorTexture::new(self.ke)))));
+        }
+        if self.dissolve < 1.0 || matches!(self.illum, 4 | 6 | 7) {
+            return Ok(Arc::new(Dielectric::new(self.ni)));
+        }
+        if max(self.ks) > 0.0 && (self.illum == 3 || max(self.kd) == 0.0) {
+            //Nsが大きいほど鏡面に近い
+            let fuzz = (2.0 / (self.ns + 2.0)).sqrt();
+            return Ok(Arc::new(Metal::new(Box::new(ColorTexture::new(self.ks)), fuzz)));
+        }
+        let albedo: Box<dyn Texture> = match &self.map_kd {
+            Some(file) => Box::new(
+                ImageTexture::load(&file.to_string_lossy())
+                    .map_err(|e| ObjError::new(path, 0, format!("map_Kd {}: {}", file.display(), e)))?,
+            ),
+            None => Box::new(ColorTexture::new(self.kd)),
+        };
+        Ok(Arc::new(Lambertian::new(albedo)))
+    }
+}
+
+fn load_mtl(path: &Path) -> Result<HashMap<String, Arc<dyn Material>>, ObjError> {
+    let text = fs::read_to_string(path).map_err(|e| ObjError::new(path, 0, e.to_string()))?;
+
+    let mut entries: Vec<(String, MtlEntry)> = Vec::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let err = |msg: String| ObjError::new(path, line, msg);
+        let mut tokens = raw.split('#').next().unwrap_or("").split_whitespace();
+        let Some(keyword) = tokens.next() else { continue };
+        let args: Vec<&str> = tokens.collect();
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return Err(err("newmtl needs a name".to_string()));
+            }
+            entries.push((args.join(" "), MtlEntry::new()));
+            continue;
+        }
+        let Some((_, entry)) = entries.last_mut() else {
+            return Err(err(format!("'{}' before any newmtl", keyword)));
+        };
+        match keyword {
+            "Kd" => entry.kd = parse_vec3(&args).map_err(err)?,
+            "Ks" => entry.ks = parse_vec3(&args).map_err(err)?,
+            "Ke" => entry.ke = parse_vec3(&args).map_err(err)?,
+            "Ni" => entry.ni = parse_scalar(&args).map_err(err)?,
+            "Ns" => entry.ns = parse_scalar(&args).map_err(err)?,
+            "d" => entry.dissolve = parse_scalar(&args).map_err(err)?,
+            "Tr" => entry.dissolve = 1.0 - parse_scalar(&args).map_err(err)?,
+            "illum" => entry.illum = parse_scalar(&args).map_err(err)? as i64,
+            "map_Kd" => {
+                // オプション(-s等)は読み飛ばし、最後をファイル名とする
+                let Some(name) = args.last() else {
+                    return Err(err("map_Kd needs a file name".to_string()));
+                };
+                entry.map_kd = Some(sibling(path, name));
+            }
+            _ => {}
+        }
+    }
+
+    let mut materials = HashMap::new();
+    for (name, entry) in entries {
+        materials.insert(name, entry.build(path)?);
+    }
+    Ok(materials)
+}
+
+// OBJ/MTLからの相対パスを解決する
+fn sibling(path: &Path, name: &str) -> PathBuf {
+    path.parent().unwrap_or(Path::new("")).join(name)
+}
+
+fn parse_f64(s: &str) -> Result<f64, String> {
+    s.parse::<f64>().map_err(|_| format!("invalid number '{}'", s))
+}
+
+fn parse_scalar(args: &[&str]) -> Result<f64, String> {
+    match args.first() {
+        Some(s) => parse_f64(s),
+        None => Err("missing value".to_string()),
+    }
+}
+
+fn parse_vec3(args: &[&str]) -> Result<Vec3, String> {
+    if args.len() < 3 {
+        return Err(format!("expected 3 values, got {}", args.len()));
+    }
+    Ok(Vec3::new(parse_f64(args[0])?, parse_f64(args[1])?, parse_f64(args[2])?))
+}
+
+// 1始まり・負数は末尾からの相対参照
+fn parse_index(s: &str, len: usize, what: &str) -> Result<usize, String> {
+    let i: i64 = s.parse().map_err(|_| format!("invalid {} index '{}'", what, s))?;
+    let index = if i > 0 { i - 1 } else { len as i64 + i };
+    if i == 0 || index < 0 || index >= len as i64 {
+        return Err(format!("{} index {} out of range (have {})", what, i, len));
+    }
+    Ok(index as usize)
+}
+
+// v, v/vt, v//vn, v/vt/vn
+fn parse_corner(
+    s: &str,
+    nv: usize,
+    nt: usize,
+    nn: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut parts = s.split('/');
+    let v = parse_index(parts.next().unwrap_or(""), nv, "vertex")?;
+    let t = match parts.next() {
+        Some(p) if !p.is_empty() => Some(parse_index(p, nt, "texcoord")?),
+        _ => None,
+    };
+    let n = match parts.next() {
+        Some(p) if !p.is_empty() => Some(parse_index(p, nn, "normal")?),
+        _ => None,
+    };
+    if parts.next().is_some() {
+        return Err(format!("malformed face vertex '{}'", s));
+    }
+    Ok((v, t, n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 一時ディレクトリにファイルを書き出す。名前はテストごとに変える
+    fn write_temp(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustray_objload_{}_{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn load(path: &Path) -> Result<BVH, ObjError> {
+        let material = Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::full(0.5)))));
+        load_obj(&path.to_string_lossy(), material, &BVHBuilder::new(BVHSplit::Sah, false))
+    }
+
+    fn load_err(name: &str, text: &str) -> ObjError {
+        let path = write_temp(name, text);
+        let result = load(&path);
+        let _ = fs::remove_file(&path);
+        match result {
+            Ok(_) => panic!("{} should not load", name),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn loads_quad_as_two_triangles() {
+        let path = write_temp("quad.obj", "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1 -1//1\n");
+        let bvh = load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(bvh.stats().primitives, 2);
+        let hit = bvh.hit(&Ray::new(Vec3::new(0.25, 0.75, 1.0), -Vec3::zaxis()), EPS, f64::MAX).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn vertex_index_out_of_range() {
+        let e = load_err("index.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n");
+        assert_eq!(e.line, 4);
+        assert!(e.message.contains("vertex index 4 out of range"), "{}", e);
+    }
+
+    #[test]
+    fn zero_index_is_rejected() {
+        let e = load_err("zero.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n");
+        assert_eq!(e.line, 4);
+        assert!(e.message.contains("vertex index 0"), "{}", e);
+    }
+
+    #[test]
+    fn normal_index_out_of_range() {
+        let e = load_err("normal.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1//1 2//1 3//1\n");
+        assert_eq!(e.line, 4);
+        assert!(e.message.contains("normal index 1 out of range"), "{}", e);
+    }
+
+    #[test]
+    fn invalid_number() {
+        let e = load_err("number.obj", "v 0 0 0\nv 1 x 0\n");
+        assert_eq!(e.line, 2);
+        assert!(e.message.contains("invalid number 'x'"), "{}", e);
+    }
+
+    #[test]
+    fn usemtl_before_mtllib() {
+        let e = load_err("usemtl.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n");
+        assert_eq!(e.line, 4);
+        assert!(e.message.contains("undefined material 'red'"), "{}", e);
+    }
+
+    #[test]
+    fn missing_obj_file() {
+        let path = std::env::temp_dir().join("rustray_objload_does_not_exist.obj");
+        let e = match load(&path) {
+            Ok(_) => panic!("missing file should not load"),
+            Err(e) => e,
+        };
+        assert_eq!(e.line, 0);
+        assert_eq!(e.path, path.display().to_string());
+    }
+
+    #[test]
+    fn missing_mtl_file_names_the_library() {
+        let e = load_err("mtllib.obj", "mtllib rustray_objload_no_such.mtl\n");
+        assert_eq!(e.line, 0);
+        assert!(e.path.ends_with("rustray_objload_no_such.mtl"), "{}", e);
+    }
+
+    #[test]
+    fn mtl_statement_before_newmtl() {
+        let mtl = write_temp("early.mtl", "Kd 1 0 0\n");
+        let name = mtl.file_name().unwrap().to_string_lossy().to_string();
+        let e = load_err("early.obj", &format!("mtllib {}\n", name));
+        let _ = fs::remove_file(&mtl);
+        assert_eq!(e.line, 1);
+        assert!(e.message.contains("'Kd' before any newmtl"), "{}", e);
+    }
+
+    #[test]
+    fn no_faces() {
+        let e = load_err("empty.obj", "v 0 0 0\n");
+        assert!(e.message.contains("no faces"), "{}", e);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::raymod::*;
 use getopts::Options;
 use std::process;
 
@@ -9,6 +10,13 @@ pub struct Args {
     pub w: usize,
     pub m: usize,
     pub output: String,
+    pub scene: Option<String>,
+    pub bvh: BVHSplit,
+    pub bvh_stats: bool,
+    pub depth: i64,
+    pub rr_depth: i64,
+    pub lights: LightSampling,
+    pub spectral: bool,
 }
 
 fn print_usage(exe_name: &str, opts: &Options) {
@@ -17,6 +25,14 @@ fn print_usage(exe_name: &str, opts: &Options) {
     process::exit(0);
 }
 
+// 不正な値ならメッセージとヘルプを表示して異常終了
+fn usage_error(exe_name: &str, opts: &Options, message: &str) -> ! {
+    let brief = format!("Usage: {}  [Options]", exe_name);
+    eprintln!("{}", message);
+    eprint!("{}", opts.usage(&brief));
+    process::exit(2);
+}
+
 pub fn parameters() -> Args {
     // コマンドラインオプションを取得
     let args: Vec<String> = std::env::args().collect();
@@ -27,6 +43,13 @@ pub fn parameters() -> Args {
     opts.optopt("w", "width", "screen width", "ex)768");
     opts.optopt("m", "model", "model number", "0..9");
     opts.optopt("o", "output", "set output file name", "[FILE]");
+    opts.optopt("", "scene", "load scene description file (overrides -m)", "[FILE]");
+    opts.optopt("b", "bvh", "bvh builder", "sah|random");
+    opts.optflag("", "bvh-stats", "print bvh build statistics");
+    opts.optopt("d", "depth", "max path depth", "ex)32");
+    opts.optopt("", "rr-depth", "depth to start russian roulette", "ex)5");
+    opts.optopt("", "lights", "how to pick one of many lights", "uniform|power|bvh");
+    opts.optflag("", "spectral", "trace wavelengths instead of rgb (dispersion)");
     opts.optflag("h", "help", "print this help");
 
     // パース
@@ -56,12 +79,35 @@ pub fn parameters() -> Args {
         .parse()
         .unwrap();
     let output = matches.opt_str("o").unwrap_or("image.png".to_string());
+    let scene = matches.opt_str("scene");
+    let bvh = match matches.opt_str("b").unwrap_or("sah".to_string()).as_str() {
+        "sah" => BVHSplit::Sah,
+        "random" => BVHSplit::Random,
+        other => usage_error(&args[0], &opts, &format!("unknown bvh builder: {}", other)),
+    };
+    let bvh_stats = matches.opt_present("bvh-stats");
+    let depth = matches
+        .opt_str("d")
+        .unwrap_or("32".to_string())
+        .parse()
+        .unwrap();
+    let rr_depth = matches
+        .opt_str("rr-depth")
+        .unwrap_or("5".to_string())
+        .parse()
+        .unwrap();
+    let lights = match matches.opt_str("lights").unwrap_or("bvh".to_string()).as_str() {
+        "uniform" => LightSampling::Uniform,
+        "power" => LightSampling::Power,
+        "bvh" => LightSampling::Bvh,
+        other => usage_error(&args[0], &opts, &format!("unknown light sampling: {}", other)),
+    };
+    let spectral = matches.opt_present("spectral");
     // 位置引数の取得
     //    let repeat = matches.free[0].clone().parse::<usize>().unwrap_or_else(|f| panic!("{}",f.to_string()));
 
     // 構造体の生成
-    let ret = Args { s, w, m, output };
-    return ret;
+    Args { s, w, m, output, scene, bvh, bvh_stats, depth, rr_depth, lights, spectral }
 }
 #[allow(dead_code)]
 fn test() {
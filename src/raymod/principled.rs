@@ -0,0 +1,232 @@
+use crate::raymod::*;
+use std::sync::Arc;
+
+// クリアコートのGTR1分布(Burley 2012)
+fn gtr1_d(h: Vec3, alpha: f64) -> f64 {
+    if h.z <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * h.z * h.z))
+}
+
+// D(h)cosθに比例してハーフベクトルを選ぶ
+fn sample_gtr1(alpha: f64) -> Vec3 {
+    let a2 = alpha * alpha;
+    let cos_theta = ((1.0 - a2.powf(1.0 - random())) / (1.0 - a2)).max(0.0).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let (sin_phi, cos_phi) = (2.0 * PI * random()).sin_cos();
+    Vec3::new(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta)
+}
+
+fn schlick_weight(cos: f64) -> f64 {
+    (1.0 - cos).clamp(0.0, 1.0).powi(5)
+}
+
+fn mix(a: Color, b: Color, t: f64) -> Color {
+    a * (1.0 - t) + b * t
+}
+
+// ヒット点でテクスチャを評価したパラメータ
+struct Params {
+    base: Color,
+    metallic: f64,
+    roughness: f64,
+    specular: f64,
+    specular_tint: f64,
+    sheen: f64,
+    sheen_tint: f64,
+    clearcoat: f64,
+    clearcoat_gloss: f64,
+    transmission: f64,
+    ior: f64,
+}
+
+impl Params {
+    fn ggx(&self) -> GGX {
+        //roughness=0でもサンプリングが破綻しないように下限を付ける
+        GGX::from_roughness(self.roughness.max(0.03), 0.0)
+    }
+    fn clearcoat_alpha(&self) -> f64 {
+        0.1 * (1.0 - self.clearcoat_gloss) + 0.001 * self.clearcoat_gloss
+    }
+    // 色相だけを取り出した色
+    fn tint(&self) -> Color {
+        let lum = 0.2126 * self.base.x + 0.7152 * self.base.y + 0.0722 * self.base.z;
+        if lum > 0.0 { self.base / lum } else { Color::full(1.0) }
+    }
+    // 各ローブの重み: 拡散、鏡面反射、ガラス、クリアコート
+    fn weights(&self) -> [f64; 4] {
+        let dielectric = 1.0 - self.metallic;
+        [
+            dielectric * (1.0 - self.transmission),
+            1.0 - dielectric * self.transmission,
+            dielectric * self.transmission,
+            0.25 * self.clearcoat,
+        ]
+    }
+}
+
+/// Disney principled BSDF (Burley 2012/2015) with diffuse, sheen, specular,
+/// clearcoat and rough transmission lobes. Every parameter may be textured;
+/// scalar parameters use the texture's x component.
+pub struct Principled {
+    pub base_color: Box<dyn Texture>,
+    pub metallic: Box<dyn Texture>,
+    pub roughness: Box<dyn Texture>,
+    pub specular: Box<dyn Texture>,
+    pub specular_tint: Box<dyn Texture>,
+    pub sheen: Box<dyn Texture>,
+    pub sheen_tint: Box<dyn Texture>,
+    pub clearcoat: Box<dyn Texture>,
+    pub clearcoat_gloss: Box<dyn Texture>,
+    pub transmission: Box<dyn Texture>,
+    pub ior: Box<dyn Texture>,
+}
+
+impl Principled {
+    pub fn new(base_color: Box<dyn Texture>) -> Self {
+        let scalar = |v: f64| -> Box<dyn Texture> { Box::new(ColorTexture::new(Vec3::full(v))) };
+        Self {
+            base_color,
+            metallic: scalar(0.0),
+            roughness: scalar(0.5),
+            specular: scalar(0.5),
+            specular_tint: scalar(0.0),
+            sheen: scalar(0.0),
+            sheen_tint: scalar(0.5),
+            clearcoat: scalar(0.0),
+            clearcoat_gloss: scalar(1.0),
+            transmission: scalar(0.0),
+            ior: scalar(1.5),
+        }
+    }
+
+    fn params(&self, hit: &HitInfo) -> Params {
+        let s = |t: &dyn Texture| t.value(hit.u, hit.v, hit.p).x;
+        Params {
+            base: self.base_color.value(hit.u, hit.v, hit.p),
+            metallic: s(self.metallic.as_ref()).clamp(0.0, 1.0),
+            roughness: s(self.roughness.as_ref()).clamp(0.0, 1.0),
+            specular: s(self.specular.as_ref()).max(0.0),
+            specular_tint: s(self.specular_tint.as_ref()).clamp(0.0, 1.0),
+            sheen: s(self.sheen.as_ref()).max(0.0),
+            sheen_tint: s(self.sheen_tint.as_ref()).clamp(0.0, 1.0),
+            clearcoat: s(self.clearcoat.as_ref()).max(0.0),
+            clearcoat_gloss: s(self.clearcoat_gloss.as_ref()).clamp(0.0, 1.0),
+            transmission: s(self.transmission.as_ref()).clamp(0.0, 1.0),
+            ior: s(self.ior.as_ref()).max(1.0),
+        }
+    }
+
+    //外から入るならior、中から出るなら1/ior
+    fn eta(p: &Params, ray: &Ray, hit: &HitInfo) -> f64 {
+        if ray.d.dot(&hit.n) < 0.0 { p.ior } else { 1.0 / p.ior }
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let p = self.params(hit);
+        let glass = GgxDielectricPdf::new(ray, hit, p.ggx(), Self::eta(&p, ray, hit));
+        let pdf = PrincipledPdf::new(glass, p.clearcoat_alpha(), p.weights());
+        Some(ScatterInfo::new(*ray, p.base, Some(Arc::new(pdf))))
+    }
+
+    fn bsdf(&self, ray: &Ray, hit: &HitInfo, _scatter: &ScatterInfo, scattered: &Ray) -> Color {
+        let p = self.params(hit);
+        let (onb, wo) = shading_frame(ray, hit);
+        let wi = onb.to_local(scattered.d.norm());
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return Color::zero();
+        }
+        let ggx = p.ggx();
+        let eta = Self::eta(&p, ray, hit);
+        let [w_diffuse, w_specular, w_glass, w_clearcoat] = p.weights();
+        if wi.z < 0.0 {
+            //裏側へは屈折だけ。透過光はベースカラーで色が付く
+            return p.base * (w_glass * ggx_dielectric(&ggx, wo, wi, eta));
+        }
+
+        let h = (wo + wi).norm();
+        let cos_d = wi.dot(&h);
+        let tint = p.tint();
+        //Burleyの拡散。粗いほど斜めから見た時に明るくなる
+        let fd90 = 0.5 + 2.0 * cos_d * cos_d * p.roughness;
+        let (fl, fv) = (schlick_weight(wi.z), schlick_weight(wo.z));
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let diffuse = p.base * (fd * FRAC_1_PI * wi.z);
+        let sheen = mix(Color::full(1.0), tint, p.sheen_tint) * (p.sheen * schlick_weight(cos_d) * wi.z);
+
+        let f0 = mix(mix(Color::full(1.0), tint, p.specular_tint) * (0.08 * p.specular), p.base, p.metallic);
+        let specular = ggx_reflection(&ggx, wo, wi, |c| fresnel_schlick(c, f0));
+        let glass = ggx_dielectric(&ggx, wo, wi, eta);
+
+        //クリアコートは屈折率1.5(F0=0.04)、Gはα=0.25で固定
+        let coat = GGX::new(0.25, 0.25);
+        let clearcoat = gtr1_d(h, p.clearcoat_alpha())
+            * fresnel_schlick(cos_d, Color::full(0.04)).x
+            * coat.g1(wo)
+            * coat.g1(wi)
+            / (4.0 * wo.z);
+
+        (diffuse + sheen) * w_diffuse
+            + specular * w_specular
+            + Color::full(glass * w_glass + clearcoat * w_clearcoat)
+    }
+}
+
+// ローブの重みに比例して1つ選び、密度は全ローブの混合で返す
+pub struct PrincipledPdf {
+    glass: GgxDielectricPdf,
+    clearcoat_alpha: f64,
+    select: [f64; 4],
+}
+
+impl PrincipledPdf {
+    fn new(glass: GgxDielectricPdf, clearcoat_alpha: f64, weights: [f64; 4]) -> Self {
+        let total: f64 = weights.iter().sum();
+        let select = if total > 0.0 { weights.map(|w| w / total) } else { [1.0, 0.0, 0.0, 0.0] };
+        Self { glass, clearcoat_alpha, select }
+    }
+}
+
+impl Pdf for PrincipledPdf {
+    fn value(&self, hit: &HitInfo, direction: Vec3) -> f64 {
+        let (wo, wi) = (self.glass.wo, self.glass.onb.to_local(direction.norm()));
+        let [diffuse, specular, glass, clearcoat] = self.select;
+        let mut pdf = 0.0;
+        if glass > 0.0 {
+            pdf += glass * self.glass.value(hit, direction);
+        }
+        if wo.z <= 0.0 {
+            return pdf;
+        }
+        if wi.z > 0.0 {
+            pdf += diffuse * wi.z * FRAC_1_PI;
+        }
+        //反射のローブも裏側に出ることがあるので、半球で切らずに密度を足す
+        let h = (wo + wi).norm();
+        if wo.dot(&h) > 0.0 {
+            let ggx = &self.glass.ggx;
+            pdf += specular * ggx.g1(wo) * ggx.d(h) / (4.0 * wo.z);
+            pdf += clearcoat * gtr1_d(h, self.clearcoat_alpha) * h.z / (4.0 * wo.dot(&h));
+        }
+        pdf
+    }
+    fn generate(&self, hit: &HitInfo) -> Vec3 {
+        let wo = self.glass.wo;
+        let [diffuse, specular, glass, _] = self.select;
+        let u = random();
+        let h = if u < diffuse {
+            return self.glass.onb.local(Vec3::random_cosine_direction());
+        } else if u < diffuse + specular {
+            self.glass.ggx.sample_visible(wo)
+        } else if u < diffuse + specular + glass {
+            return self.glass.generate(hit);
+        } else {
+            sample_gtr1(self.clearcoat_alpha)
+        };
+        self.glass.onb.local(h * 2.0 * wo.dot(&h) - wo)
+    }
+}
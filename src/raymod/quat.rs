@@ -2,6 +2,7 @@ use crate::raymod::*;
 
 
 /// A quaternion
+#[derive(Clone, Copy, Debug)]
 pub struct Quat(Vec3, f64);
 
  #[allow(dead_code)]
@@ -72,7 +73,7 @@ impl Quat {
     }
 
     /// Returns as array
-    pub fn to_array(&self) -> [f64; 4] {
+    pub fn to_array(self) -> [f64; 4] {
         [self.0.x, self.0.y, self.0.z, self.1]
     }
 
@@ -83,7 +84,7 @@ impl Quat {
         let x = (w1 * x2 + y1 * z2) - (z1 * y2);
         let y = (w1 * y2 + z1 * x2) - (x1 * z2);
         let z = (w1 * z2 + x1 * y2) - (y1 * x2);
-        let w = (x1 * x2 + y1 * y2) - (z1 * z2);
+        let w = (x1 * x2 + y1 * y2) + (z1 * z2);
         Vec3::new(
             ((w * x1 + x * w1) - y * z1) + z * y1,
             ((w * y1 + y * w1) - z * x1) + x * z1,
@@ -91,14 +92,94 @@ impl Quat {
         )
     }
 
-    /// Compute linear interpolation between two quaternions
-    pub fn lerp(&self, _other: Quat, _t: f64) -> Self {
-        todo!()
+    /// Compute normalized linear interpolation between two quaternions (shortest path)
+    pub fn lerp(&self, other: Quat, t: f64) -> Self {
+        let other = if self.dot(other) < 0.0 { Quat(-other.0, -other.1) } else { other };
+        Quat(self.0 * (1.0 - t) + other.0 * t, self.1 * (1.0 - t) + other.1 * t).normalize()
     }
 
-    /// Compute spherical linear interpolation between two quaternions
-    pub fn slerp(&self, _other: Quat, _t: f64) -> Self {
-        todo!()
+    /// Compute spherical linear interpolation between two quaternions (shortest path)
+    pub fn slerp(&self, other: Quat, t: f64) -> Self {
+        let mut cos_theta = self.dot(other);
+        let other = if cos_theta < 0.0 {
+            cos_theta = -cos_theta;
+            Quat(-other.0, -other.1)
+        } else {
+            other
+        };
+        // ほぼ同じ向きならlerpで十分
+        if cos_theta > 0.9995 {
+            return self.lerp(other, t);
+        }
+        let theta = cos_theta.acos();
+        let recip = theta.sin().recip();
+        let a = ((1.0 - t) * theta).sin() * recip;
+        let b = (t * theta).sin() * recip;
+        Quat(self.0 * a + other.0 * b, self.1 * a + other.1 * b)
+    }
+
+    /// Construct from any axis (normalized here) and radians
+    pub fn from_axis_angle(axis: Vec3, rad: f64) -> Self {
+        Quat::from_rot(axis.norm(), rad)
+    }
+
+    /// Returns the unit rotation axis and angle in radians
+    pub fn to_axis_angle(self) -> (Vec3, f64) {
+        let q = self.normalize();
+        let w = q.1.clamp(-1.0, 1.0);
+        let s = (1.0 - w * w).sqrt();
+        if s < EPS {
+            // 回転なし、軸は何でもよい
+            (Vec3::xaxis(), 0.0)
+        } else {
+            (q.0 / s, 2.0 * w.acos())
+        }
+    }
+
+    /// Construct from Euler angles in radians, applied in x, y, z order
+    pub fn from_euler(x: f64, y: f64, z: f64) -> Self {
+        Quat::from_rot_z(z) * Quat::from_rot_y(y) * Quat::from_rot_x(x)
+    }
+
+    /// Returns Euler angles (x, y, z) in radians, inverse of `from_euler`
+    pub fn to_euler(self) -> (f64, f64, f64) {
+        let [x, y, z, w] = self.normalize().to_array();
+        let x_rot = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+        let y_rot = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin();
+        let z_rot = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+        (x_rot, y_rot, z_rot)
+    }
+
+    /// Returns as a rotation matrix
+    pub fn to_matrix(self) -> Matrix4 {
+        let [x, y, z, w] = self.normalize().to_array();
+        Matrix4::new([
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y), 0.0],
+            [2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x), 0.0],
+            [2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// Construct from the upper 3x3 rotation part of a matrix
+    pub fn from_matrix(m: &Matrix4) -> Self {
+        let m = &m.m;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        // 対角成分の大きいものを基準にして桁落ちを避ける
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quat::new((m[2][1] - m[1][2]) / s, (m[0][2] - m[2][0]) / s, (m[1][0] - m[0][1]) / s, 0.25 * s)
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+            Quat::new(0.25 * s, (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s, (m[2][1] - m[1][2]) / s)
+        } else if m[1][1] > m[2][2] {
+            let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+            Quat::new((m[0][1] + m[1][0]) / s, 0.25 * s, (m[1][2] + m[2][1]) / s, (m[0][2] - m[2][0]) / s)
+        } else {
+            let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+            Quat::new((m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, 0.25 * s, (m[1][0] - m[0][1]) / s)
+        };
+        q.normalize()
     }
 }
 
@@ -110,84 +191,118 @@ impl std::ops::Mul<Quat> for Quat {
         let [x2, y2, z2, w2] = rhs.to_array();
         Quat::new(
             w1 * x2 + x1 * w2 + y1 * z2 - z1 * y2,
-            w1 * y2 + y2 * w2 + z1 * x2 - x1 * z2,
+            w1 * y2 + y1 * w2 + z1 * x2 - x1 * z2,
             w1 * z2 + z1 * w2 + x1 * y2 - y1 * x2,
             w1 * w2 - x1 * x2 - y1 * y2 - z1 * z2,
         )
     }
 }
 
-
-pub struct Translate {
-    pub shape: Box<dyn Shape>,
-    pub offset: Vec3,
-}
+// Transformedを作る簡易コンストラクタ
+pub struct Translate;
+#[allow(clippy::new_ret_no_self)]
 impl Translate {
-    pub fn new(shape: Box<dyn Shape>, offset: Vec3) -> Self {
-        Self { shape, offset }
-    }
-}
-impl Shape for Translate {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let moved_ray = Ray::new(ray.o - self.offset, ray.d);
-        if let Some(hit) = self.shape.hit(&moved_ray, t0, t1) {
-            Some(HitInfo { p: hit.p + self.offset, ..hit })
-        } else {
-            None
-        }
+    pub fn new(shape: Box<dyn Shape>, offset: Vec3) -> Transformed {
+        Transformed::translate(shape, offset)
     }
-    fn bounding_box(&self) -> Option<AABB> {
-        if let Some(aabb)=self.shape.bounding_box() {
-            let min = aabb.min +self.offset;
-            let max = aabb.max +self.offset;
-            Some(AABB{min,max})
-        }else{
-            None
-        }
+    /// Keyframed translation: (time, offset) pairs, linearly interpolated
+    pub fn keyframed(shape: Box<dyn Shape>, keys: Vec<(f64, Vec3)>) -> Animated {
+        Animated::translate(shape, keys)
     }
 }
-pub struct Rotate {
-    pub shape: Box<dyn Shape>,
-    pub quat: Quat,
-}
+
+pub struct Rotate;
+#[allow(clippy::new_ret_no_self)]
 impl Rotate {
-    pub fn new(shape: Box<dyn Shape>, axis: Vec3, angle: f64) -> Self {
-        Self { shape, quat: Quat::from_rot(axis, angle.to_radians()) }
+    pub fn new(shape: Box<dyn Shape>, axis: Vec3, angle: f64) -> Transformed {
+        Transformed::rotate(shape, axis, angle)
+    }
+    /// Keyframed rotation about a unit axis: (time, degrees) pairs, linearly interpolated
+    pub fn keyframed(shape: Box<dyn Shape>, axis: Vec3, keys: Vec<(f64, f64)>) -> Animated {
+        Animated::rotate(shape, axis, keys)
     }
 }
-impl Shape for Rotate {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let revq = self.quat.conj();
-        let rotated_ray = Ray::new(revq.rotate(ray.o), revq.rotate(ray.d));
-        if let Some(hit) = self.shape.hit(&rotated_ray, t0, t1) {
-            Some(HitInfo { p: self.quat.rotate(hit.p), n: self.quat.rotate(hit.n), ..hit })
-        } else {
-            None
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    // qと-qは同じ回転
+    fn assert_same_rotation(a: Quat, b: Quat) {
+        assert!((a.dot(b).abs() - 1.0).abs() < 1e-12, "{:?} != {:?}", a, b);
+    }
+
+    fn assert_vec_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-20, "{:?} != {:?}", a, b);
+    }
+
+    fn samples() -> Vec<Quat> {
+        let mut qs = vec![Quat::unit(), Quat::from_rot_x(PI), Quat::from_rot_y(PI), Quat::from_rot_z(PI)];
+        for i in 0..20 {
+            let t = i as f64;
+            let axis = Vec3::new((t * 1.3).sin(), (t * 0.7).cos(), 0.3 + t * 0.1);
+            qs.push(Quat::from_axis_angle(axis, -3.0 + t * 0.31));
         }
+        qs
     }
-    fn bounding_box(&self) -> Option<AABB> {
-        if let Some(aabb)=self.shape.bounding_box() {
-            let mut min=Vec3::new( f64::INFINITY, f64::INFINITY, f64::INFINITY);
-            let mut max=Vec3::new(-f64::INFINITY,-f64::INFINITY,-f64::INFINITY);
-            for i in 0..2 {
-                for j in 0..2 {
-                    for k in 0..2{
-                        let x=i as f64 * aabb.max.x + (1-i)as f64 * aabb.min.x;
-                        let y=j as f64 * aabb.max.y + (1-j)as f64 * aabb.min.y;
-                        let z=k as f64 * aabb.max.z + (1-k)as f64 * aabb.min.z;
-                        let tester = self.quat.rotate(Vec3{x,y,z});
-                        min.x=min.x.min(tester.x);
-                        max.x=max.x.max(tester.x);
-                        min.y=min.y.min(tester.y);
-                        max.y=max.y.max(tester.y);
-                        min.z=min.z.min(tester.z);
-                        max.z=max.z.max(tester.z);
-                    }
+
+    #[test]
+    fn euler_round_trip() {
+        for &x in &[-3.0, -1.2, 0.0, 0.4, 2.9] {
+            for &y in &[-1.5, -0.6, 0.0, 0.8, 1.5] {
+                for &z in &[-2.5, 0.0, 0.3, 3.1] {
+                    let q = Quat::from_euler(x, y, z);
+                    let (ex, ey, ez) = q.to_euler();
+                    assert!((ex - x).abs() < 1e-9 && (ey - y).abs() < 1e-9 && (ez - z).abs() < 1e-9);
+                    //x, y, zの順にかかる
+                    let p = Vec3::new(0.3, -0.5, 0.8);
+                    let expected = Quat::from_rot_z(z).rotate(Quat::from_rot_y(y).rotate(Quat::from_rot_x(x).rotate(p)));
+                    assert_vec_near(q.rotate(p), expected);
                 }
             }
-            Some(AABB{min,max})
-        }else{
-            None
         }
     }
+
+    #[test]
+    fn matrix_round_trip() {
+        let p = Vec3::new(1.0, 2.0, -0.5);
+        for q in samples() {
+            let m = q.to_matrix();
+            assert_same_rotation(Quat::from_matrix(&m), q);
+            assert_vec_near(Transform::from_quat(&q).point(p), q.rotate(p));
+        }
+    }
+
+    #[test]
+    fn axis_angle_round_trip() {
+        for q in samples() {
+            let (axis, angle) = q.to_axis_angle();
+            assert!((axis.length() - 1.0).abs() < 1e-12 || angle == 0.0);
+            assert_same_rotation(Quat::from_axis_angle(axis, angle), q);
+        }
+    }
+
+    #[test]
+    fn slerp_endpoints() {
+        let qs = samples();
+        for w in qs.windows(2) {
+            let (a, b) = (w[0], w[1]);
+            assert_same_rotation(a.slerp(b, 0.0), a);
+            assert_same_rotation(a.slerp(b, 1.0), b);
+            let mid = a.slerp(b, 0.5);
+            assert!((mid.length() - 1.0).abs() < 1e-12);
+            assert!((mid.dot(a).abs() - mid.dot(b).abs()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn slerp_takes_shortest_path() {
+        //270度回転は-90度回転と同じなので、途中は-45度になる
+        let b = Quat::from_rot_z(1.5 * PI);
+        assert!(Quat::unit().dot(b) < 0.0);
+        let expected = Vec3::new((-PI / 4.0).cos(), (-PI / 4.0).sin(), 0.0);
+        assert_vec_near(Quat::unit().slerp(b, 0.5).rotate(Vec3::xaxis()), expected);
+        assert_vec_near(Quat::unit().lerp(b, 0.5).rotate(Vec3::xaxis()), expected);
+    }
 }
@@ -9,11 +9,21 @@ const PI2:f64 = PI*2.0;
 pub struct Ray {
     pub o: Vec3,
     pub d: Vec3,
+    pub time: f64,
+    //スペクトルモードで運ぶ波長[nm]。RGBモードでは0
+    pub wavelength: f64,
 }
 
 impl Ray {
     pub fn new(o: Vec3, d: Vec3) -> Ray {
-        Ray { o, d }
+        Ray { o, d, time: 0.0, wavelength: 0.0 }
+    }
+    /// Construct a ray at the given time within the shutter interval
+    pub fn with_time(o: Vec3, d: Vec3, time: f64) -> Ray {
+        Ray { o, d, time, wavelength: 0.0 }
+    }
+    pub fn with_wavelength(self, wavelength: f64) -> Ray {
+        Ray { wavelength, ..self }
     }
     pub fn at(&self, t: f64) -> Vec3 {
         self.o + self.d * t
@@ -39,8 +49,45 @@ impl HitInfo {
 pub trait Shape: Send + Sync {
     fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo>;
     fn bounding_box(&self) -> Option<AABB>;
-    fn pdf_value(&self, _o: Vec3, _v: Vec3) -> f64 { 0.0 }
-    fn random(&self, _o: Vec3) -> Vec3 { Vec3::xaxis() }
+    /// Solid-angle density of `random` for direction `v` seen from `o` at `time`
+    fn pdf_value(&self, _o: Vec3, _v: Vec3, _time: f64) -> f64 { 0.0 }
+    fn random(&self, _o: Vec3, _time: f64) -> Vec3 { Vec3::xaxis() }
+    /// True if the shape emits light and can be sampled with `pdf_value`/`random`
+    fn is_emissive(&self) -> bool { false }
+    /// Emissive shapes inside a container, shared so they can also be sampled as lights
+    fn emitters(&self) -> Vec<Arc<dyn Shape>> { Vec::new() }
+    /// Surface area, used to estimate the power of a light
+    fn area(&self) -> f64 { 0.0 }
+    /// Average emitted radiance, used to estimate the power of a light
+    fn emission(&self) -> Color { Color::zero() }
+}
+
+//光源リストとワールドで同じ形状を共有する
+impl Shape for Arc<dyn Shape> {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        self.as_ref().hit(ray, t0, t1)
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        self.as_ref().bounding_box()
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.as_ref().pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.as_ref().random(o, time)
+    }
+    fn is_emissive(&self) -> bool {
+        self.as_ref().is_emissive()
+    }
+    fn emitters(&self) -> Vec<Arc<dyn Shape>> {
+        self.as_ref().emitters()
+    }
+    fn area(&self) -> f64 {
+        self.as_ref().area()
+    }
+    fn emission(&self) -> Color {
+        self.as_ref().emission()
+    }
 }
 //法線逆転用
 pub struct FlipFace {
@@ -62,6 +109,25 @@ impl Shape for FlipFace {
     fn bounding_box(&self) -> Option<AABB> {
         self.shape.bounding_box() 
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.shape.pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.shape.random(o, time)
+    }
+    fn is_emissive(&self) -> bool {
+        self.shape.is_emissive()
+    }
+    //光源のサンプリングでは面の向きは関係ない
+    fn emitters(&self) -> Vec<Arc<dyn Shape>> {
+        self.shape.emitters()
+    }
+    fn area(&self) -> f64 {
+        self.shape.area()
+    }
+    fn emission(&self) -> Color {
+        self.shape.emission()
+    }
 }
 
 pub struct Sphere {
@@ -83,11 +149,9 @@ impl Sphere {
         let theta = p.y.asin();
         (1.0 - (phi + PI) / (2.0 * PI), (theta + PI / 2.0) / PI)
     }
-}
-
-impl Shape for Sphere {
-    fn hit(&self, r: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let oc = r.o - self.center;
+    //中心を指定して交差判定(MovingSphereと共有)
+    fn hit_center(&self, center: Vec3, r: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let oc = r.o - center;
         let a = r.d.dot(&r.d);
         let b = r.d.dot(&oc) * 2.0;
         let c = oc.dot(&oc) - self.radius * self.radius;
@@ -98,30 +162,24 @@ impl Shape for Sphere {
             let temp = (-b - root) / (2.0 * a);
             if temp < t1 && temp > t0 {
                 let p = r.at(temp);
-                let n = (p - self.center) / self.radius;
+                let n = (p - center) / self.radius;
                 let (u, v) = Self::uv(n);
                 return Some(HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v));
             }
             let temp = (-b + root) / (2.0 * a);
             if temp < t1 && temp > t0 {
                 let p = r.at(temp);
-                let n = (p - self.center) / self.radius;
+                let n = (p - center) / self.radius;
                 let (u, v) = Self::uv(n);
                 return Some(HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v));
             }
         }
         None
     }
-    fn bounding_box(&self) -> Option<AABB> {
-        let radius = Vec3::new(self.radius, self.radius, self.radius);
-        let min = self.center - radius;
-        let max = self.center + radius;
-        Some(AABB { min, max })
-    }
-
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
-        if let Some(_) = self.hit(&Ray::new(o, v), EPS10, f64::MAX) {
-            let dd = (self.center - o).length();
+    //中心を指定して光源としてサンプリング(MovingSphereと共有)
+    fn pdf_center(&self, center: Vec3, o: Vec3, v: Vec3) -> f64 {
+        if self.hit_center(center, &Ray::new(o, v), EPS10, f64::MAX).is_some() {
+            let dd = (center - o).length();
             let rr = self.radius.powi(2).min(dd);
             let cos_theta_max = (1.0 - rr * dd.recip()).sqrt();
             let solid_angle = PI2 * (1.0 - cos_theta_max);
@@ -130,13 +188,128 @@ impl Shape for Sphere {
             0.0
         }
     }
-    fn random(&self, o: Vec3) -> Vec3 {
-        let direction = self.center - o;
+    fn random_center(&self, center: Vec3, o: Vec3) -> Vec3 {
+        let direction = center - o;
         let distance_squared = direction.length();
         ONB::new(direction).local(Vec3::random_to_sphere(self.radius, distance_squared))
     }
 }
 
+impl Shape for Sphere {
+    fn hit(&self, r: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        self.hit_center(self.center, r, t0, t1)
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        let radius = Vec3::new(self.radius, self.radius, self.radius);
+        let min = self.center - radius;
+        let max = self.center + radius;
+        Some(AABB { min, max })
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3, _time: f64) -> f64 {
+        self.pdf_center(self.center, o, v)
+    }
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
+        self.random_center(self.center, o)
+    }
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+    fn emission(&self) -> Color {
+        self.material.emission()
+    }
+}
+
+//時刻time0からtime1の間にcenter0からcenter1へ等速で動く球
+pub struct MovingSphere {
+    pub sphere: Sphere,
+    pub center1: Vec3,
+    pub time0: f64,
+    pub time1: f64,
+}
+
+impl MovingSphere {
+    pub fn new(center0: Vec3, center1: Vec3, time0: f64, time1: f64, radius: f64, material: Arc<dyn Material>) -> Self {
+        Self { sphere: Sphere::new(center0, radius, material), center1, time0, time1 }
+    }
+    pub fn center(&self, time: f64) -> Vec3 {
+        if self.time1 == self.time0 {
+            return self.sphere.center;
+        }
+        let s = ((time - self.time0) / (self.time1 - self.time0)).clamp(0.0, 1.0);
+        self.sphere.center + (self.center1 - self.sphere.center) * s
+    }
+}
+
+impl Shape for MovingSphere {
+    fn hit(&self, r: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        self.sphere.hit_center(self.center(r.time), r, t0, t1)
+    }
+    //動く範囲全体を覆う
+    fn bounding_box(&self) -> Option<AABB> {
+        let radius = Vec3::full(self.sphere.radius);
+        let box0 = AABB::new(self.sphere.center - radius, self.sphere.center + radius);
+        let box1 = AABB::new(self.center1 - radius, self.center1 + radius);
+        Some(surrounding_box(&box0, &box1))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.sphere.pdf_center(self.center(time), o, v)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.sphere.random_center(self.center(time), o)
+    }
+    fn is_emissive(&self) -> bool {
+        self.sphere.is_emissive()
+    }
+    fn area(&self) -> f64 {
+        self.sphere.area()
+    }
+    fn emission(&self) -> Color {
+        self.sphere.emission()
+    }
+}
+
+// 密度一定の媒質。境界形状の中を指数分布の距離で散乱する
+// 境界は凸形状を想定(入って出るまでを一区間とみなす)
+pub struct ConstantMedium {
+    pub boundary: Box<dyn Shape>,
+    pub neg_inv_density: f64,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl ConstantMedium {
+    pub fn new(boundary: Box<dyn Shape>, density: f64, phase_function: Arc<dyn Material>) -> Self {
+        Self { boundary, neg_inv_density: -density.recip(), phase_function }
+    }
+}
+
+impl Shape for ConstantMedium {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let hit1 = self.boundary.hit(ray, -f64::INFINITY, f64::INFINITY)?;
+        let hit2 = self.boundary.hit(ray, hit1.t + EPS, f64::INFINITY)?;
+        let enter = hit1.t.max(t0);
+        let exit = hit2.t.min(t1);
+        if enter >= exit {
+            return None;
+        }
+        let ray_length = ray.d.length().sqrt();
+        let distance_inside = (exit - enter) * ray_length;
+        let hit_distance = self.neg_inv_density * random().ln();
+        if hit_distance > distance_inside {
+            return None;
+        }
+        let t = enter + hit_distance / ray_length;
+        //法線は意味を持たないので適当な向き
+        Some(HitInfo::new(t, ray.at(t), Vec3::xaxis(), Arc::clone(&self.phase_function), 0.0, 0.0))
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        self.boundary.bounding_box()
+    }
+}
+
 pub enum RectAxisType {
     XY,
     XZ,
@@ -219,7 +392,7 @@ impl Shape for Rect {
         }
         Some(AABB { min, max })
     }
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+    fn pdf_value(&self, o: Vec3, v: Vec3, _time: f64) -> f64 {
         if let Some(hit) = self.hit(&Ray::new(o, v), EPS10, f64::MAX) {
             let area = (self.x1 - self.x0) * (self.y1 - self.y0);
             let distance_squared = hit.t.powi(2) * v.length();
@@ -229,7 +402,7 @@ impl Shape for Rect {
             0.0
         }
     }
-    fn random(&self, o: Vec3) -> Vec3 {
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
         let rx=random();let ry=random();
         let x = self.x0 + rx * (self.x1 - self.x0);
         let y = self.y0 + ry * (self.y1 - self.y0);
@@ -239,13 +412,22 @@ impl Shape for Rect {
             RectAxisType::YZ => Vec3::new(self.k, x, y) - o,
         }
     }
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+    fn area(&self) -> f64 {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+    fn emission(&self) -> Color {
+        self.material.emission()
+    }
 }
 
-
 pub struct RectAngle {
     p_min:Vec3,
     p_max:Vec3,
     shapes:ShapeList,
+    material: Arc<dyn Material>,
 }
 
 impl RectAngle {
@@ -271,7 +453,7 @@ impl RectAngle {
                 Rect::new(p_min.y,p_max.y,p_min.z,p_max.z,p_min.x,RectAxisType::YZ,Arc::clone(&material))
             ))
         ));
-        Self { p_min,p_max,shapes}
+        Self { p_min,p_max,shapes,material}
     }
 }
 
@@ -284,6 +466,23 @@ impl Shape for RectAngle {
         let max = self.p_max;
         Some(AABB { min, max })
     }
+    //6面から一様に選ぶ
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.shapes.pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.shapes.random(o, time)
+    }
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+    fn area(&self) -> f64 {
+        let d = self.p_max - self.p_min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+    fn emission(&self) -> Color {
+        self.material.emission()
+    }
 }
 
 pub struct ShapeList {
@@ -299,6 +498,27 @@ impl ShapeList {
     pub fn push(&mut self, object: Box<dyn Shape>) {
         self.objects.push(object);
     }
+
+    /// Share the emissive objects, and the emitters inside containers such as
+    /// meshes and instances, with a new light list. None if there are none.
+    pub fn lights(&mut self, sampling: LightSampling) -> Option<Arc<dyn Shape>> {
+        let mut lights = ShapeList::new();
+        self.objects = std::mem::take(&mut self.objects)
+            .into_iter()
+            .map(|object| {
+                if !object.is_emissive() {
+                    for emitter in object.emitters() {
+                        lights.push(Box::new(emitter));
+                    }
+                    return object;
+                }
+                let shared: Arc<dyn Shape> = Arc::from(object);
+                lights.push(Box::new(Arc::clone(&shared)));
+                Box::new(shared) as Box<dyn Shape>
+            })
+            .collect();
+        if lights.objects.is_empty() { None } else { Some(build_lights(lights.objects, sampling)) }
+    }
 }
 
 impl Shape for ShapeList {
@@ -318,10 +538,7 @@ impl Shape for ShapeList {
             Some(first) => {
                 match first.bounding_box() {
                     Some(bbox) => self.objects.iter().skip(1).try_fold(bbox, |acc, shape| {
-                        match shape.bounding_box() {
-                            Some(bbox) => Some(surrounding_box(&acc, &bbox)),
-                            _ => None,
-                        }
+                        shape.bounding_box().map(|bbox| surrounding_box(&acc, &bbox))
                     }),
                     _ => None,
                 }
@@ -329,14 +546,86 @@ impl Shape for ShapeList {
             _ => None,
         }
     }
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
         if self.objects.is_empty() { panic!(); }
         let weight = 1.0 / self.objects.len() as f64;
-        self.objects.iter().fold(0.0,|acc, s| acc + weight * s.pdf_value(o, v))
+        self.objects.iter().fold(0.0,|acc, s| acc + weight * s.pdf_value(o, v, time))
     }
-    fn random(&self, o: Vec3) -> Vec3 {
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
         if self.objects.is_empty() { panic!(); }
         let index = (random() * self.objects.len() as f64).floor() as usize;
-        self.objects[index].random(o)
+        self.objects[index].random(o, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light() -> Arc<dyn Material> {
+        Arc::new(DiffuseLight::new(Box::new(ColorTexture::new(Vec3::full(4.0)))))
+    }
+
+    fn white() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::full(0.5)))))
+    }
+
+    fn triangle_at(c: Vec3, material: Arc<dyn Material>) -> Box<dyn Shape> {
+        Box::new(Triangle::from_points(c, c + Vec3::xaxis(), c + Vec3::zaxis(), material))
+    }
+
+    fn bvh(shapes: Vec<Box<dyn Shape>>) -> BVH {
+        BVH::new(shapes, BVHSplit::Sah)
+    }
+
+    //原点から見た時にtarget(時刻time)の方向が光源として選ばれうるか
+    fn sampled(light: &Arc<dyn Shape>, target: Vec3, time: f64) -> bool {
+        light.pdf_value(Vec3::zero(), target, time) > 0.0
+    }
+
+    #[test]
+    fn lights_include_emitters_inside_containers() {
+        let mut world = ShapeList::new();
+        //メッシュ(BVH)の中の発光する三角形だけ
+        world.push(Box::new(bvh(vec![
+            triangle_at(Vec3::new(-5.0, 10.0, -5.0), light()),
+            triangle_at(Vec3::new(5.0, 10.0, -5.0), white()),
+        ])));
+        //インスタンスの中の発光する面と、材質を発光に上書きしたインスタンス
+        let mesh = Arc::new(bvh(vec![triangle_at(Vec3::zero(), light())]));
+        let dark = Arc::new(bvh(vec![triangle_at(Vec3::zero(), white())]));
+        world.push(Box::new(Instance::new(mesh, Transform::translate(Vec3::new(-5.0, 10.0, 5.0)), None)));
+        world.push(Box::new(Instance::new(dark, Transform::translate(Vec3::new(5.0, 10.0, 5.0)), Some(light()))));
+        //箱と動く光源
+        world.push(Box::new(RectAngle::new(Vec3::new(-1.0, -11.0, -1.0), Vec3::new(1.0, -9.0, 1.0), light())));
+        world.push(Box::new(MovingSphere::new(Vec3::new(10.0, 0.0, 0.0), Vec3::new(10.0, 0.0, 10.0), 0.0, 1.0, 1.0, light())));
+        let keys = vec![(0.0, Vec3::zero()), (1.0, Vec3::new(0.0, 0.0, 10.0))];
+        let sphere = Box::new(Sphere::new(Vec3::new(-10.0, 0.0, 0.0), 1.0, light()));
+        world.push(Box::new(Transformed::new(Box::new(Animated::translate(sphere, keys)), Transform::identity())));
+
+        let lights = world.lights(LightSampling::Bvh).expect("scene has lights");
+        let face = Vec3::new(0.3, 0.0, 0.3);
+        assert!(sampled(&lights, Vec3::new(-5.0, 10.0, -5.0) + face, 0.0));
+        assert!(!sampled(&lights, Vec3::new(5.0, 10.0, -5.0) + face, 0.0));
+        assert!(sampled(&lights, Vec3::new(-5.0, 10.0, 5.0) + face, 0.0));
+        assert!(sampled(&lights, Vec3::new(5.0, 10.0, 5.0) + face, 0.0));
+        assert!(sampled(&lights, Vec3::new(0.0, -9.0, 0.0), 0.0));
+        //動く光源はその時刻の位置でだけ選ばれる
+        assert!(sampled(&lights, Vec3::new(10.0, 0.0, 10.0), 1.0));
+        assert!(!sampled(&lights, Vec3::new(10.0, 0.0, 10.0), 0.0));
+        assert!(sampled(&lights, Vec3::new(-10.0, 0.0, 10.0), 1.0));
+        assert!(!sampled(&lights, Vec3::new(-10.0, 0.0, 10.0), 0.0));
+    }
+
+    #[test]
+    fn moving_sphere_samples_its_position_at_time() {
+        let moving = MovingSphere::new(Vec3::zero(), Vec3::new(0.0, 0.0, 4.0), 0.0, 1.0, 1.0, light());
+        let fixed = Sphere::new(Vec3::new(0.0, 0.0, 2.0), 1.0, light());
+        let o = Vec3::new(0.0, 5.0, 0.0);
+        for _ in 0..100 {
+            let v = moving.random(o, 0.5);
+            assert!(fixed.hit(&Ray::new(o, v), EPS10, f64::MAX).is_some());
+            assert_eq!(moving.pdf_value(o, v, 0.5), fixed.pdf_value(o, v, 0.0));
+        }
     }
 }
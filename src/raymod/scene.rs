@@ -2,6 +2,7 @@ use crate::raymod::*;
 use std::sync::Arc;
 
 //左上が原点なPNGフォーマット対応
+#[allow(dead_code)]
 #[derive(Copy, Clone, Debug)]
 pub struct Camera {
     pub origin: Vec3,
@@ -12,6 +13,8 @@ pub struct Camera {
     pub u: Vec3,
     pub v: Vec3,
     pub w: Vec3,
+    pub time0: f64,
+    pub time1: f64,
 }
 
 impl Camera {
@@ -48,22 +51,31 @@ impl Camera {
             u,
             v,
             w,
+            time0: 0.0,
+            time1: 0.0,
         }
     }
 
+    /// Set the shutter open/close times; rays are sampled uniformly in between
+    pub fn with_shutter(self, time0: f64, time1: f64) -> Camera {
+        Camera { time0, time1, ..self }
+    }
+
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
         let rd = Vec3::random_in_unit_disk() * self.lens_radius;
         let offset = rd.x*self.u + rd.y*self.v ;
-        Ray::new(
+        let time = self.time0 + random() * (self.time1 - self.time0);
+        Ray::with_time(
             self.origin + offset,
             self.upper_left_corner + s * self.horizontal - t * self.vertical - self.origin - offset,
+            time,
         )
     }
 }
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::vec_init_then_push)]
 impl ShapeList {
-    pub fn cornell_dielectric_scene(&mut self) -> Camera {
+    pub fn cornell_dielectric_scene(&mut self, bvh: &BVHBuilder) -> Camera {
         let red = Color::new(0.64, 0.05, 0.05);
         let white = Color::new(0.73,0.73,0.73);
         let green = Color::new(0.12, 0.45, 0.15);
@@ -150,7 +162,7 @@ impl ShapeList {
                     Vec3::new(0.0,1.0,0.0),15.0)
             ),Vec3::new(265.0, 0.0, 295.0) )    
         ));
-        self.push(Box::new(BVH::new(box_list1)));
+        self.push(Box::new(bvh.build(box_list1)));
 
         
         // simple_scene用カメラ
@@ -161,7 +173,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -169,9 +181,9 @@ impl ShapeList {
             SQUARE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
-    pub fn cornell_mirror_box_scene(&mut self) -> Camera {
+    pub fn cornell_mirror_box_scene(&mut self, bvh: &BVHBuilder) -> Camera {
         let red = Color::new(0.64, 0.05, 0.05);
         let white = Color::new(0.73,0.73,0.73);
         let green = Color::new(0.12, 0.45, 0.15);
@@ -267,7 +279,7 @@ impl ShapeList {
                     Vec3::new(0.0,1.0,0.0),15.0)
             ),Vec3::new(265.0, 0.0, 295.0) )    
         ));
-        self.push(Box::new(BVH::new(box_list1)));
+        self.push(Box::new(bvh.build(box_list1)));
 
         
         // simple_scene用カメラ
@@ -278,7 +290,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -286,7 +298,7 @@ impl ShapeList {
             SQUARE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
     pub fn random_scene(&mut self) -> Camera {
         self.push(Box::new(Sphere::new(
@@ -297,7 +309,6 @@ impl ShapeList {
             ))))),
         )));
 
-        let mut box_list1: Vec<Box<dyn Shape>> = Vec::new();
         for a in -11..11 {
             for b in -11..11 {
                 let choose_mat = random();
@@ -306,7 +317,7 @@ impl ShapeList {
                     if choose_mat < 0.8 {
                         // diffuse
                         let albedo = Vec3::random().mult(Vec3::random());
-                        box_list1.push(Box::new(Sphere::new(
+                        self.push(Box::new(Sphere::new(
                             center,
                             0.2,
                             Arc::new(Lambertian::new(Box::new(ColorTexture::new(albedo)))),
@@ -315,14 +326,14 @@ impl ShapeList {
                         // Metal
                         let fuzz = random_range(0.0, 0.5);
                         let albedo = Vec3::vec3_random_range(0.5, 1.0);
-                        box_list1.push(Box::new(Sphere::new(
+                        self.push(Box::new(Sphere::new(
                             center,
                             0.2,
                             Arc::new(Metal::new(Box::new(ColorTexture::new(albedo)), fuzz)),
                         )));
                     } else {
                         // glass
-                        box_list1.push(Box::new(Sphere::new(
+                        self.push(Box::new(Sphere::new(
                             center,
                             0.2,
                             Arc::new(Dielectric::new(1.5)),
@@ -331,8 +342,6 @@ impl ShapeList {
                 }
             }
         }
-        self.push(Box::new(BVH::new(box_list1)));
-
         self.push(Box::new(Sphere::new(
             Vec3::new(0.0, 1.0, 0.0),
             1.0,
@@ -362,7 +371,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -370,14 +379,25 @@ impl ShapeList {
             WIDE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
 
 }
 
+// シーンはデータだけを持ち、色の計算はIntegratorが行う
 pub trait Scene : Send + Sync{
-    fn ray_color(&self,r: &Ray,depth: i64,) -> Vec3;
-    fn get_ray(&self,u:f64,v:f64)->Ray;
+    fn camera(&self) -> &Camera;
+    fn world(&self) -> &dyn Shape;
+    /// Emissive shapes sampled directly for next event estimation, if any
+    fn light(&self) -> Option<&Arc<dyn Shape>>;
+    fn background(&self) -> Color;
+    /// Environment map used instead of `background` for rays that escape, if any
+    fn environment(&self) -> Option<&EnvMap> { None }
+    /// Point, spot and directional lights that have no geometry
+    fn punctual_lights(&self) -> &[Box<dyn Light>] { &[] }
+    fn get_ray(&self,u:f64,v:f64)->Ray {
+        self.camera().get_ray(u,v)
+    }
 }
 
 pub struct RandomScene {
@@ -387,187 +407,69 @@ pub struct RandomScene {
 }
 
 impl RandomScene {
-    pub fn new()->Self {
+    pub fn new(bvh: &BVHBuilder)->Self {
+        //地面の球も含めて一つのBVHにまとめる
+        let mut list = ShapeList::new();
+        let cam = list.random_scene();
         let mut world = ShapeList::new();
-        let cam =world.random_scene();
+        world.push(Box::new(bvh.build(list.objects)));
         let background=Vec3::new(0.7,0.8,1.0);
         Self { cam,world,background } 
     }
 }
 
 impl Scene for RandomScene {
-    fn get_ray(&self,u:f64,v:f64)->Ray {
-        self.cam.get_ray(u,v)
-    }
-  
-    fn ray_color(&self,r: &Ray, depth: i64) -> Vec3 {
-        if depth <= 0 {
-            return Vec3::new(0.0, 0.0, 0.0);
-        }
-        let hit_info = self.world.hit(&r, EPS, f64::MAX);
-        if let Some(hit) = hit_info {
-            let emitted = hit.m.emitted(&r, &hit);
-            let scatter_info = hit.m.scatter(r, &hit);
-            if let Some(scatter) = scatter_info {
-                 if let Some(pdf)=scatter.pdf {
-                     let new_ray = Ray::new(hit.p,pdf.generate(&hit));
-                     emitted + scatter.albedo.mult(self.ray_color(&new_ray, depth - 1 ))
-                 }else{
-                     emitted + scatter.albedo.mult(self.ray_color(&scatter.ray, depth-1 )) 
-                 }
-            } else {
-                return emitted;
-            }
-        } else {
-            return self.background;
-        }
-    }
+    fn camera(&self) -> &Camera { &self.cam }
+    fn world(&self) -> &dyn Shape { &self.world }
+    fn light(&self) -> Option<&Arc<dyn Shape>> { None }
+    fn background(&self) -> Color { self.background }
 }
 
 
 pub struct CornellBoxScene {
     pub cam:Camera,
     pub world: ShapeList,
-    pub light:Arc<dyn Shape>,
+    pub light:Option<Arc<dyn Shape>>,
     pub background:Vec3,
 }
 
 impl CornellBoxScene{
-    pub fn new() -> Self {
+    pub fn new(bvh: &BVHBuilder, sampling: LightSampling) -> Self {
         let mut world = ShapeList::new();
-        let cam = world.cornell_mirror_box_scene();
-        let light = Arc::new(Rect::new(
-                            213.0, 343.0, 227.0, 332.0, 554.0,RectAxisType::XZ,
-                            Arc::new(Lambertian::new(Box::new( ColorTexture::new(Vec3::zero()) )))
-        ));
+        let cam = world.cornell_mirror_box_scene(bvh);
+        let light = world.lights(sampling);
         let background=Vec3::zero();
         Self { cam,world,light,background } 
     }
 }
 
 impl Scene for CornellBoxScene {
-    fn get_ray(&self,u:f64,v:f64)->Ray {
-        self.cam.get_ray(u,v)
-    }
-    
-    fn ray_color(&self,r: &Ray,depth: i64,) -> Vec3 {
-        if depth <= 0 { 
-            return self.background;
-        }
-        let hit_info = self.world.hit(&r, EPS, f64::MAX);
-        if let Some(hit) = hit_info {
-            let emitted = hit.m.emitted(&r, &hit);
-            let scatter_info = hit.m.scatter(r, &hit);
-            if let Some(scatter) = scatter_info {
-                if let Some(pdf)=scatter.pdf {
-                    let shape_pdf = Arc::new(ShapePdf::new(Arc::clone(&self.light), hit.p));
-                    let pdf = MixturePdf::new(shape_pdf, Arc::clone(&pdf) );
-                    let new_ray = Ray::new(hit.p,pdf.generate(&hit));
-                    let spdf_value = pdf.value(&hit,new_ray.d);
-                    if spdf_value > 0.0 {
-                        let pdf_value = hit.m.scattering_pdf(&new_ray, &hit);
-                        let albedo = scatter.albedo * pdf_value;
-                        emitted + albedo.mult(self.ray_color(&new_ray, depth-1,)) /spdf_value
-
-                    } else {
-                        emitted
-                    }
-                }else{
-                    emitted + scatter.albedo.mult(self.ray_color(&scatter.ray, depth-1 )) 
-                }
-            } else {
-                emitted
-            }
-        } else { // hit=None
-            return self.background;
-        }
-    }
-
+    fn camera(&self) -> &Camera { &self.cam }
+    fn world(&self) -> &dyn Shape { &self.world }
+    fn light(&self) -> Option<&Arc<dyn Shape>> { self.light.as_ref() }
+    fn background(&self) -> Color { self.background }
 }
+
 pub struct CornellDielectricScene {
     pub cam:Camera,
     pub world: ShapeList,
-    pub light:Arc<dyn Shape>,
+    pub light:Option<Arc<dyn Shape>>,
     pub background:Vec3,
 }
 
 impl CornellDielectricScene{
-    pub fn new() -> Self {
+    pub fn new(bvh: &BVHBuilder, sampling: LightSampling) -> Self {
         let mut world = ShapeList::new();
-        let cam = world.cornell_dielectric_scene();
-        let mut light = ShapeList::new();
-        light.push( Box::new(Sphere::new(
-            Vec3::new(190.0, 90.0, 190.0), 90.0, 
-            Arc::new(Lambertian::new(Box::new( ColorTexture::new(Vec3::zero()) )))
-        )));
-        light.push( Box::new(Rect::new(
-            213.0, 343.0, 227.0, 332.0, 554.0,RectAxisType::XZ,
-            Arc::new(Lambertian::new(Box::new( ColorTexture::new(Vec3::zero()) )))
-        )));
-        
+        let cam = world.cornell_dielectric_scene(bvh);
+        let light = world.lights(sampling);
         let background=Vec3::zero();
-        Self { cam,world,light:Arc::new(light),background } 
+        Self { cam,world,light,background } 
     }
 }
 
 impl Scene for CornellDielectricScene {
-    fn get_ray(&self,u:f64,v:f64)->Ray {
-        self.cam.get_ray(u,v)
-    }
-    
-    fn ray_color(&self, r: &Ray, depth: i64) -> Vec3 {
-        // 1. 再帰深度のチェック
-        if depth <= 0 {
-            return self.background;
-        }
-
-        // 2. レイの衝突判定
-        let hit = match self.world.hit(r, EPS, f64::MAX) {
-            Some(h) => h,
-            None => return self.background, // 衝突しない場合は背景色を返す
-        };
-
-        // 3. 放射成分の計算
-        let emitted = hit.m.emitted(r, &hit);
-
-        // 4. 散乱情報の取得
-        let scatter = match hit.m.scatter(r, &hit) {
-            Some(s) => s,
-            None => return emitted, // 散乱しない場合は放射成分のみ返す (吸収など)
-        };
-
-        // 5. PDFが存在しない場合 (PDFがない基本的な散乱)
-        let pdf_ref = match scatter.pdf {
-            Some(ref pdf) => pdf,
-            None => {
-                // Lambertianなどのシンプルな散乱モデルの処理
-                return emitted + scatter.albedo.mult(self.ray_color(&scatter.ray, depth - 1));
-            }
-        };
-
-        // 6. PDFが存在する場合 (MIS/Importance Sampling)
-        
-        // a. 混合PDF (Shape + Material PDF) の構築
-        let shape_pdf = Arc::new(ShapePdf::new(Arc::clone(&self.light), hit.p));
-        let pdf = MixturePdf::new(shape_pdf, Arc::clone(pdf_ref));
-        
-        // b. 新しいレイの生成
-        let new_ray = Ray::new(hit.p, pdf.generate(&hit));
-        
-        // c. PDF値の評価
-        let spdf_value = pdf.value(&hit, new_ray.d);
-
-        // d. 最終的な色の計算
-        if spdf_value > 0.0 {
-            let pdf_value = hit.m.scattering_pdf(&new_ray, &hit);
-            let albedo = scatter.albedo * pdf_value;
-            
-            // 重要な再帰呼び出し部分
-            return emitted + albedo.mult(self.ray_color(&new_ray, depth - 1)) / spdf_value;
-        } else {
-            // PDF値が0以下の場合は放射成分のみ
-            return emitted;
-        }
-    }
+    fn camera(&self) -> &Camera { &self.cam }
+    fn world(&self) -> &dyn Shape { &self.world }
+    fn light(&self) -> Option<&Arc<dyn Shape>> { self.light.as_ref() }
+    fn background(&self) -> Color { self.background }
 }
-
@@ -0,0 +1,834 @@
+use crate::raymod::*;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+// シーンファイルのエラー。位置が分からない場合はline,columnが0
+#[derive(Debug)]
+pub struct SceneError {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{}:{}:{}: {}", self.path, self.line, self.column, self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+type Span = Option<Range<usize>>;
+
+// TOMLのテーブルと、キーが無い時にエラーを出す位置
+#[derive(Clone, Copy)]
+struct Node<'a> {
+    table: &'a dyn TableLike,
+    span: &'a Span,
+    //読んだキー。最後まで読まれなかったキーは未知のキーとして報告する
+    read: &'a RefCell<HashSet<String>>,
+}
+
+struct SceneParser<'a> {
+    path: &'a Path,
+    src: &'a str,
+    bvh: &'a BVHBuilder,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    meshes: HashMap<String, Arc<BVH>>,
+}
+
+impl<'a> SceneParser<'a> {
+    fn error(&self, span: &Span, message: impl Into<String>) -> SceneError {
+        let (line, column) = match span {
+            Some(r) => {
+                let before = &self.src[..r.start.min(self.src.len())];
+                let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+                (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+            }
+            None => (0, 0),
+        };
+        SceneError { path: self.path.display().to_string(), line, column, message: message.into() }
+    }
+
+    // シーンファイルからの相対パス
+    fn file(&self, name: &str) -> PathBuf {
+        self.path.parent().unwrap_or(Path::new("")).join(name)
+    }
+
+    // テーブルをNodeとして読み、fが読まなかったキーがあればエラーにする
+    fn node<T>(
+        &self,
+        table: &dyn TableLike,
+        span: &Span,
+        f: impl FnOnce(Node) -> Result<T, SceneError>,
+    ) -> Result<T, SceneError> {
+        let read = RefCell::new(HashSet::new());
+        let value = f(Node { table, span, read: &read })?;
+        self.unknown_keys(table, &read.into_inner())?;
+        Ok(value)
+    }
+
+    fn unknown_keys(&self, table: &dyn TableLike, read: &HashSet<String>) -> Result<(), SceneError> {
+        match table.iter().find(|(key, _)| !read.contains(*key)) {
+            Some((key, item)) => {
+                let span = table.key(key).and_then(|k| k.span()).or_else(|| item.span());
+                Err(self.error(&span, format!("unknown key '{}'", key)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn item<'t>(&self, node: Node<'t>, key: &str) -> Option<&'t Item> {
+        node.read.borrow_mut().insert(key.to_string());
+        node.table.get(key)
+    }
+
+    fn has(&self, node: Node, key: &str) -> bool {
+        self.item(node, key).is_some()
+    }
+
+    fn get<'t>(&self, node: Node<'t>, key: &str) -> Result<&'t Item, SceneError> {
+        self.item(node, key)
+            .ok_or_else(|| self.error(node.span, format!("missing key '{}'", key)))
+    }
+
+    fn value<'t>(&self, item: &'t Item) -> Result<&'t Value, SceneError> {
+        item.as_value()
+            .ok_or_else(|| self.error(&item.span(), format!("expected a value, found {}", item.type_name())))
+    }
+
+    fn number(&self, v: &Value) -> Result<f64, SceneError> {
+        match v {
+            Value::Float(f) => Ok(*f.value()),
+            Value::Integer(i) => Ok(*i.value() as f64),
+            _ => Err(self.error(&v.span(), format!("expected a number, found {}", v.type_name()))),
+        }
+    }
+
+    fn f64(&self, node: Node, key: &str) -> Result<f64, SceneError> {
+        let item = self.get(node, key)?;
+        self.number(self.value(item)?)
+    }
+
+    fn f64_or(&self, node: Node, key: &str, default: f64) -> Result<f64, SceneError> {
+        if self.has(node, key) { self.f64(node, key) } else { Ok(default) }
+    }
+
+    fn bool_or(&self, node: Node, key: &str, default: bool) -> Result<bool, SceneError> {
+        match self.item(node, key) {
+            Some(item) => item
+                .as_bool()
+                .ok_or_else(|| self.error(&item.span(), format!("'{}' must be true or false", key))),
+            None => Ok(default),
+        }
+    }
+
+    fn str<'t>(&self, node: Node<'t>, key: &str) -> Result<(&'t str, Span), SceneError> {
+        let item = self.get(node, key)?;
+        match item.as_str() {
+            Some(s) => Ok((s, item.span())),
+            None => Err(self.error(&item.span(), format!("'{}' must be a string", key))),
+        }
+    }
+
+    fn vec3_value(&self, v: &Value) -> Result<Vec3, SceneError> {
+        match v.as_array() {
+            Some(a) if a.len() == 3 => Ok(Vec3::new(
+                self.number(a.get(0).unwrap())?,
+                self.number(a.get(1).unwrap())?,
+                self.number(a.get(2).unwrap())?,
+            )),
+            _ => Err(self.error(&v.span(), "expected an array of 3 numbers")),
+        }
+    }
+
+    fn vec3(&self, node: Node, key: &str) -> Result<Vec3, SceneError> {
+        let item = self.get(node, key)?;
+        self.vec3_value(self.value(item)?)
+    }
+
+    fn vec3_or(&self, node: Node, key: &str, default: Vec3) -> Result<Vec3, SceneError> {
+        if self.has(node, key) { self.vec3(node, key) } else { Ok(default) }
+    }
+
+    fn camera(&self, node: Node) -> Result<(Camera, f64), SceneError> {
+        let lookfrom = self.vec3(node, "lookfrom")?;
+        let lookat = self.vec3(node, "lookat")?;
+        let vup = self.vec3_or(node, "vup", Vec3::yaxis())?;
+        let vfov = self.f64(node, "vfov")?;
+        let aspect = self.f64_or(node, "aspect", WIDE_ASPECT)?;
+        let aperture = self.f64_or(node, "aperture", 0.0)?;
+        let focus_dist = self.f64_or(node, "focus_dist", (lookfrom - lookat).length().sqrt())?;
+        let shutter_open = self.f64_or(node, "shutter_open", 0.0)?;
+        let shutter_close = self.f64_or(node, "shutter_close", shutter_open)?;
+        let cam = Camera::new(lookfrom, lookat, vup, vfov, aspect, aperture, focus_dist)
+            .with_shutter(shutter_open, shutter_close);
+        Ok((cam, aspect))
+    }
+
+    fn texture_ref(&self, node: Node, key: &str) -> Result<Arc<dyn Texture>, SceneError> {
+        let (name, span) = self.str(node, key)?;
+        match self.textures.get(name) {
+            Some(t) => Ok(Arc::clone(t)),
+            None => Err(self.error(&span, format!("undefined texture '{}'", name))),
+        }
+    }
+
+    fn texture(&self, node: Node) -> Result<Arc<dyn Texture>, SceneError> {
+        let (kind, span) = self.str(node, "type")?;
+        match kind {
+            "color" => Ok(Arc::new(ColorTexture::new(self.vec3(node, "color")?))),
+            "checker" => Ok(Arc::new(CheckerTexture::new(
+                Box::new(self.texture_ref(node, "odd")?),
+                Box::new(self.texture_ref(node, "even")?),
+                self.f64_or(node, "freq", 10.0)?,
+            ))),
+            "image" => {
+                let (name, span) = self.str(node, "file")?;
+                let file = self.file(name);
+                match ImageTexture::load(&file.to_string_lossy()) {
+                    Ok(t) => Ok(Arc::new(t)),
+                    Err(e) => Err(self.error(&span, format!("{}: {}", file.display(), e))),
+                }
+            }
+            _ => Err(self.error(&span, format!("unknown texture type '{}'", kind))),
+        }
+    }
+
+    // texture = "名前" か color = [r,g,b] のどちらか
+    fn albedo(&self, node: Node) -> Result<Box<dyn Texture>, SceneError> {
+        if self.has(node, "texture") {
+            Ok(Box::new(self.texture_ref(node, "texture")?))
+        } else {
+            Ok(Box::new(ColorTexture::new(self.vec3(node, "color")?)))
+        }
+    }
+
+    // 数値か名前付きテクスチャ。数値なら全成分が同じ色テクスチャにする
+    fn scalar_texture(&self, node: Node, key: &str, default: f64) -> Result<Box<dyn Texture>, SceneError> {
+        match self.item(node, key) {
+            Some(item) if item.as_str().is_some() => Ok(Box::new(self.texture_ref(node, key)?)),
+            Some(item) => Ok(Box::new(ColorTexture::new(Vec3::full(self.number(self.value(item)?)?)))),
+            None => Ok(Box::new(ColorTexture::new(Vec3::full(default)))),
+        }
+    }
+
+    // absorption = [r,g,b] は中を距離absorption_distance進んだ時の透過色
+    fn absorption(&self, node: Node) -> Result<Absorption, SceneError> {
+        if !self.has(node, "absorption") {
+            return Ok(Absorption::none());
+        }
+        let distance = self.f64_or(node, "absorption_distance", 1.0)?;
+        if distance <= 0.0 {
+            return Err(self.error(&self.get(node, "absorption_distance")?.span(), "absorption_distance must be positive"));
+        }
+        Ok(Absorption::from_color(self.vec3(node, "absorption")?, distance))
+    }
+
+    // 名前付きのガラス(Sellmeier)か、riとcauchy_b[μm²]からのCauchyの式
+    fn dispersion(&self, node: Node) -> Result<Dispersion, SceneError> {
+        if self.has(node, "dispersion") {
+            if self.has(node, "cauchy_b") {
+                return Err(self.error(&self.get(node, "cauchy_b")?.span(), "cauchy_b cannot be used with dispersion"));
+            }
+            let (name, span) = self.str(node, "dispersion")?;
+            return Dispersion::preset(name).ok_or_else(|| self.error(&span, format!("unknown dispersion '{}'", name)));
+        }
+        if self.has(node, "cauchy_b") {
+            return Ok(Dispersion::cauchy(self.f64_or(node, "ri", 1.5)?, self.f64(node, "cauchy_b")?));
+        }
+        Ok(Dispersion::None)
+    }
+
+    fn material(&self, node: Node) -> Result<Arc<dyn Material>, SceneError> {
+        let (kind, span) = self.str(node, "type")?;
+        match kind {
+            "lambertian" => Ok(Arc::new(Lambertian::new(self.albedo(node)?))),
+            "oren_nayar" => Ok(Arc::new(OrenNayar::new(self.albedo(node)?, self.scalar_texture(node, "sigma", 0.3)?))),
+            "metal" => Ok(Arc::new(Metal::new(self.albedo(node)?, self.f64_or(node, "fuzz", 0.0)?))),
+            "conductor" => {
+                let (eta, k) = if self.has(node, "metal") {
+                    let (name, name_span) = self.str(node, "metal")?;
+                    conductor_preset(name)
+                        .ok_or_else(|| self.error(&name_span, format!("unknown metal '{}'", name)))?
+                } else {
+                    (self.vec3(node, "eta")?, self.vec3(node, "k")?)
+                };
+                let ggx = GGX::from_roughness(
+                    self.f64_or(node, "roughness", 0.0)?,
+                    self.f64_or(node, "anisotropic", 0.0)?,
+                );
+                Ok(Arc::new(Conductor::new(eta, k, ggx)))
+            }
+            "principled" => {
+                let mut m = Principled::new(self.albedo(node)?);
+                m.metallic = self.scalar_texture(node, "metallic", 0.0)?;
+                m.roughness = self.scalar_texture(node, "roughness", 0.5)?;
+                m.specular = self.scalar_texture(node, "specular", 0.5)?;
+                m.specular_tint = self.scalar_texture(node, "specular_tint", 0.0)?;
+                m.sheen = self.scalar_texture(node, "sheen", 0.0)?;
+                m.sheen_tint = self.scalar_texture(node, "sheen_tint", 0.5)?;
+                m.clearcoat = self.scalar_texture(node, "clearcoat", 0.0)?;
+                m.clearcoat_gloss = self.scalar_texture(node, "clearcoat_gloss", 1.0)?;
+                m.transmission = self.scalar_texture(node, "transmission", 0.0)?;
+                m.ior = self.scalar_texture(node, "ior", 1.5)?;
+                Ok(Arc::new(m))
+            }
+            "coated" => Ok(Arc::new(Coated::new(
+                self.material_ref(node, "base")?,
+                self.f64_or(node, "ior", 1.5)?,
+                self.f64_or(node, "roughness", 0.0)?,
+                self.f64_or(node, "thickness", 0.0)?,
+                self.vec3_or(node, "color", Vec3::full(1.0))?,
+            ))),
+            "dielectric" => Ok(Arc::new(
+                Dielectric::new(self.f64_or(node, "ri", 1.5)?)
+                    .with_tint(self.vec3_or(node, "tint", Vec3::full(1.0))?)
+                    .with_absorption(self.absorption(node)?)
+                    .with_dispersion(self.dispersion(node)?),
+            )),
+            "rough_dielectric" => Ok(Arc::new(
+                RoughDielectric::new(self.f64_or(node, "ri", 1.5)?, self.scalar_texture(node, "roughness", 0.3)?)
+                    .with_tint(self.vec3_or(node, "tint", Vec3::full(1.0))?)
+                    .with_absorption(self.absorption(node)?),
+            )),
+            "diffuse_light" => Ok(Arc::new(DiffuseLight::new(self.albedo(node)?))),
+            "isotropic" => Ok(Arc::new(Isotropic::new(self.albedo(node)?))),
+            _ => Err(self.error(&span, format!("unknown material type '{}'", kind))),
+        }
+    }
+
+    fn material_ref(&self, node: Node, key: &str) -> Result<Arc<dyn Material>, SceneError> {
+        let (name, span) = self.str(node, key)?;
+        match self.materials.get(name) {
+            Some(m) => Ok(Arc::clone(m)),
+            None => Err(self.error(&span, format!("undefined material '{}'", name))),
+        }
+    }
+
+    fn shape(&self, node: Node) -> Result<Box<dyn Shape>, SceneError> {
+        let (kind, span) = self.str(node, "type")?;
+        let mut shape: Box<dyn Shape> = match kind {
+            "sphere" => Box::new(Sphere::new(
+                self.vec3(node, "center")?,
+                self.f64(node, "radius")?,
+                self.material_ref(node, "material")?,
+            )),
+            "moving_sphere" => Box::new(MovingSphere::new(
+                self.vec3(node, "center0")?,
+                self.vec3(node, "center1")?,
+                self.f64_or(node, "time0", 0.0)?,
+                self.f64_or(node, "time1", 1.0)?,
+                self.f64(node, "radius")?,
+                self.material_ref(node, "material")?,
+            )),
+            "rect" => {
+                let (axis, axis_span) = self.str(node, "axis")?;
+                let axis = match axis {
+                    "xy" => RectAxisType::XY,
+                    "xz" => RectAxisType::XZ,
+                    "yz" => RectAxisType::YZ,
+                    _ => return Err(self.error(&axis_span, format!("unknown rect axis '{}'", axis))),
+                };
+                Box::new(Rect::new(
+                    self.f64(node, "x0")?,
+                    self.f64(node, "x1")?,
+                    self.f64(node, "y0")?,
+                    self.f64(node, "y1")?,
+                    self.f64(node, "k")?,
+                    axis,
+                    self.material_ref(node, "material")?,
+                ))
+            }
+            "box" => Box::new(RectAngle::new(
+                self.vec3(node, "min")?,
+                self.vec3(node, "max")?,
+                self.material_ref(node, "material")?,
+            )),
+            "triangle" => Box::new(Triangle::from_points(
+                self.vec3(node, "p0")?,
+                self.vec3(node, "p1")?,
+                self.vec3(node, "p2")?,
+                self.material_ref(node, "material")?,
+            )),
+            "obj" => {
+                let (name, file_span) = self.str(node, "file")?;
+                Box::new(self.load_obj(node, name, &file_span)?)
+            }
+            "instance" => {
+                let (name, mesh_span) = self.str(node, "mesh")?;
+                let mesh = match self.meshes.get(name) {
+                    Some(m) => Arc::clone(m),
+                    None => return Err(self.error(&mesh_span, format!("undefined mesh '{}'", name))),
+                };
+                let material = if self.has(node, "material") { Some(self.material_ref(node, "material")?) } else { None };
+                //インスタンスは変換を自分で持つ。motionがある時はその外側で変換する
+                let transform =
+                    if self.has(node, "motion") { Transform::identity() } else { self.transform(node)? };
+                Box::new(Instance::new(mesh, transform, material))
+            }
+            _ => return Err(self.error(&span, format!("unknown shape type '{}'", kind))),
+        };
+        //densityがあれば形状を境界とする媒質にする(materialが位相関数)
+        if self.has(node, "density") {
+            let density = self.f64(node, "density")?;
+            if density <= 0.0 {
+                return Err(self.error(&self.get(node, "density")?.span(), "density must be positive"));
+            }
+            shape = Box::new(ConstantMedium::new(shape, density, self.material_ref(node, "material")?));
+        }
+        if self.bool_or(node, "flip", false)? {
+            shape = Box::new(FlipFace::new(shape));
+        }
+        //motionはローカル座標で、transformより先にかける
+        let moving = self.has(node, "motion");
+        if moving {
+            shape = Box::new(self.motion(node, shape)?);
+        }
+        if (kind != "instance" || moving) && self.has(node, "transform") {
+            shape = Box::new(Transformed::new(shape, self.transform(node)?));
+        }
+        Ok(shape)
+    }
+
+    // motion = { type = "translate", keys = [{ time, offset }, ...] }
+    //        | { type = "rotate", axis, keys = [{ time, angle }, ...] }
+    fn motion(&self, node: Node, shape: Box<dyn Shape>) -> Result<Animated, SceneError> {
+        let item = self.get(node, "motion")?;
+        let span = item.span();
+        let table = item
+            .as_table_like()
+            .ok_or_else(|| self.error(&span, "'motion' must be a table"))?;
+        self.node(table, &span, |m| {
+            let (kind, kind_span) = self.str(m, "type")?;
+            let keys_item = self.get(m, "keys")?;
+            let keys = match keys_item.as_array() {
+                Some(a) if !a.is_empty() => a,
+                _ => return Err(self.error(&keys_item.span(), "'keys' must be a non-empty array of tables")),
+            };
+            let mut offsets = Vec::new();
+            let mut angles = Vec::new();
+            for v in keys.iter() {
+                let key_span = v.span();
+                let k = v
+                    .as_inline_table()
+                    .ok_or_else(|| self.error(&key_span, "keyframe must be a table"))?;
+                self.node(k, &key_span, |k| {
+                    let time = self.f64(k, "time")?;
+                    match kind {
+                        "translate" => offsets.push((time, self.vec3(k, "offset")?)),
+                        "rotate" => angles.push((time, self.f64(k, "angle")?)),
+                        _ => return Err(self.error(&kind_span, format!("unknown motion type '{}'", kind))),
+                    }
+                    Ok(())
+                })?;
+            }
+            if kind == "translate" {
+                Ok(Translate::keyframed(shape, offsets))
+            } else {
+                Ok(Rotate::keyframed(shape, self.vec3(m, "axis")?.norm(), angles))
+            }
+        })
+    }
+
+    // transformは書かれた順に適用する
+    fn transform(&self, node: Node) -> Result<Transform, SceneError> {
+        let Some(item) = self.item(node, "transform") else { return Ok(Transform::identity()) };
+        let list = item
+            .as_array()
+            .ok_or_else(|| self.error(&item.span(), "'transform' must be an array of tables"))?;
+        let mut transform = Transform::identity();
+        for v in list.iter() {
+            let span = v.span();
+            let t = v
+                .as_inline_table()
+                .ok_or_else(|| self.error(&span, "transform entry must be a table"))?;
+            let step = self.node(t, &span, |t| {
+                let (kind, kind_span) = self.str(t, "type")?;
+                Ok(match kind {
+                    "translate" => Transform::translate(self.vec3(t, "offset")?),
+                    "rotate" => Transform::rotate(self.vec3(t, "axis")?.norm(), self.f64(t, "angle")?),
+                    "scale" => {
+                        let item = self.get(t, "factor")?;
+                        let v = self.value(item)?;
+                        let factor = if v.is_array() { self.vec3_value(v)? } else { Vec3::full(self.number(v)?) };
+                        if factor.x * factor.y * factor.z == 0.0 {
+                            return Err(self.error(&v.span(), "scale factor must not be zero"));
+                        }
+                        Transform::scale(factor)
+                    }
+                    "matrix" => {
+                        let item = self.get(t, "m")?;
+                        let v = self.value(item)?;
+                        let values = match v.as_array() {
+                            Some(a) if a.len() == 16 => a.iter().map(|x| self.number(x)).collect::<Result<Vec<f64>, _>>()?,
+                            _ => return Err(self.error(&v.span(), "expected an array of 16 numbers (row-major)")),
+                        };
+                        let mut m = Matrix4::identity();
+                        for (i, x) in values.into_iter().enumerate() {
+                            m.m[i / 4][i % 4] = x;
+                        }
+                        Transform::from_matrix(m).ok_or_else(|| self.error(&v.span(), "matrix is not invertible"))?
+                    }
+                    _ => return Err(self.error(&kind_span, format!("unknown transform type '{}'", kind))),
+                })
+            })?;
+            transform = step * transform;
+        }
+        Ok(transform)
+    }
+
+    // 形状を持たない光源。intensityは色(強さ込み)
+    fn light(&self, node: Node) -> Result<Box<dyn Light>, SceneError> {
+        let (kind, span) = self.str(node, "type")?;
+        let intensity = self.vec3(node, "intensity")?;
+        match kind {
+            "point" => Ok(Box::new(PointLight::new(self.vec3(node, "position")?, intensity))),
+            "spot" => {
+                let position = self.vec3(node, "position")?;
+                let direction = if self.has(node, "lookat") {
+                    self.vec3(node, "lookat")? - position
+                } else {
+                    self.vec3(node, "direction")?
+                };
+                let outer = self.f64(node, "angle")?;
+                let inner = self.f64_or(node, "inner_angle", outer)?;
+                Ok(Box::new(SpotLight::new(position, direction, intensity, inner, outer)))
+            }
+            "directional" => Ok(Box::new(DirectionalLight::new(
+                self.vec3(node, "direction")?,
+                intensity,
+                self.f64_or(node, "angle", 0.0)?,
+            ))),
+            _ => Err(self.error(&span, format!("unknown light type '{}'", kind))),
+        }
+    }
+
+    // 正距円筒図法のHDR画像。rotationは+Y軸まわりの角度
+    fn environment(&self, node: Node) -> Result<EnvMap, SceneError> {
+        let (name, span) = self.str(node, "file")?;
+        let intensity = self.f64_or(node, "intensity", 1.0)?;
+        let rotation = self.f64_or(node, "rotation", 0.0)?;
+        EnvMap::load(&self.file(name).to_string_lossy(), intensity, rotation)
+            .map_err(|e| self.error(&span, format!("{}: {}", name, e)))
+    }
+
+    // Preethamの空。sunがtrueなら同じ色の太陽も平行光源として加える
+    fn sky(&self, node: Node) -> Result<(EnvMap, Option<Box<dyn Light>>), SceneError> {
+        let elevation = self.f64(node, "elevation")?;
+        if !(0.0..=90.0).contains(&elevation) {
+            return Err(self.error(&self.get(node, "elevation")?.span(), "elevation must be between 0 and 90"));
+        }
+        let turbidity = self.f64_or(node, "turbidity", 3.0)?;
+        if !(1.7..=10.0).contains(&turbidity) {
+            return Err(self.error(&self.get(node, "turbidity")?.span(), "turbidity must be between 1.7 and 10"));
+        }
+        let sky = PreethamSky::new(elevation, self.f64_or(node, "azimuth", 0.0)?, turbidity);
+        let intensity = self.f64_or(node, "intensity", 1.0)?;
+        let ground = self.vec3_or(node, "ground", Vec3::full(0.3))?;
+        let sun: Option<Box<dyn Light>> =
+            if self.bool_or(node, "sun", true)? { Some(Box::new(sky.sun(intensity))) } else { None };
+        Ok((sky.bake(512, 256, intensity, ground), sun))
+    }
+
+    // materialが無ければ白のLambertian
+    fn load_obj(&self, node: Node, name: &str, span: &Span) -> Result<BVH, SceneError> {
+        let material = if self.has(node, "material") {
+            self.material_ref(node, "material")?
+        } else {
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(0.73, 0.73, 0.73)))))
+        };
+        load_obj(&self.file(name).to_string_lossy(), material, self.bvh).map_err(|e| self.error(span, e.to_string()))
+    }
+}
+
+pub struct FileScene {
+    pub cam: Camera,
+    pub aspect: f64,
+    pub world: ShapeList,
+    pub light: Option<Arc<dyn Shape>>,
+    pub punctual: Vec<Box<dyn Light>>,
+    pub background: Vec3,
+    pub environment: Option<EnvMap>,
+}
+
+impl FileScene {
+    /// Load a TOML scene description. Meshes and the top level are built with `bvh`,
+    /// and the emissive shapes are picked with `sampling`.
+    pub fn load(path: &str, bvh: &BVHBuilder, sampling: LightSampling) -> Result<Self, SceneError> {
+        let path = Path::new(path);
+        let src = fs::read_to_string(path).map_err(|e| SceneError {
+            path: path.display().to_string(),
+            line: 0,
+            column: 0,
+            message: e.to_string(),
+        })?;
+        Self::parse(path, &src, bvh, sampling)
+    }
+
+    // 読み込んだ文字列から作る。pathはエラー表示と相対パスの基準
+    fn parse(path: &Path, src: &str, bvh: &BVHBuilder, sampling: LightSampling) -> Result<Self, SceneError> {
+        let mut parser = SceneParser {
+            path,
+            src,
+            bvh,
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            meshes: HashMap::new(),
+        };
+        let doc = ImDocument::parse(src)
+            .map_err(|e| parser.error(&e.span(), e.message().trim_end().replace('\n', ": ")))?;
+        let root_span: Span = Some(0..0);
+        let root_read = RefCell::new(HashSet::new());
+        let root = Node { table: doc.as_table(), span: &root_span, read: &root_read };
+
+        let camera_item = parser.get(root, "camera")?;
+        let camera_span = camera_item.span();
+        let camera_table = camera_item
+            .as_table_like()
+            .ok_or_else(|| parser.error(&camera_span, "'camera' must be a table"))?;
+        let (cam, aspect) = parser.node(camera_table, &camera_span, |node| parser.camera(node))?;
+        let background = parser.vec3_or(root, "background", Vec3::zero())?;
+        let mut punctual: Vec<Box<dyn Light>> = Vec::new();
+        let environment = match (parser.item(root, "environment"), parser.item(root, "sky")) {
+            (Some(_), Some(item)) => {
+                return Err(parser.error(&item.span(), "'environment' and 'sky' cannot be used together"));
+            }
+            (Some(item), None) => {
+                let span = item.span();
+                let table = item
+                    .as_table_like()
+                    .ok_or_else(|| parser.error(&span, "'environment' must be a table"))?;
+                Some(parser.node(table, &span, |node| parser.environment(node))?)
+            }
+            (None, Some(item)) => {
+                let span = item.span();
+                let table = item.as_table_like().ok_or_else(|| parser.error(&span, "'sky' must be a table"))?;
+                let (sky, sun) = parser.node(table, &span, |node| parser.sky(node))?;
+                punctual.extend(sun);
+                Some(sky)
+            }
+            (None, None) => None,
+        };
+
+        // textures, materials, meshesは定義順に読むので、参照は先に定義しておく
+        for section in ["textures", "materials", "meshes"] {
+            let Some(item) = parser.item(root, section) else { continue };
+            let table = item
+                .as_table_like()
+                .ok_or_else(|| parser.error(&item.span(), format!("'{}' must be a table", section)))?;
+            for (name, entry) in table.iter() {
+                let span = entry.span();
+                let entry = entry
+                    .as_table_like()
+                    .ok_or_else(|| parser.error(&span, format!("{}.{} must be a table", section, name)))?;
+                match section {
+                    "textures" => {
+                        let texture = parser.node(entry, &span, |node| parser.texture(node))?;
+                        parser.textures.insert(name.to_string(), texture);
+                    }
+                    "materials" => {
+                        let material = parser.node(entry, &span, |node| parser.material(node))?;
+                        parser.materials.insert(name.to_string(), material);
+                    }
+                    _ => {
+                        let mesh = parser.node(entry, &span, |node| {
+                            let (file, file_span) = parser.str(node, "file")?;
+                            parser.load_obj(node, file, &file_span)
+                        })?;
+                        parser.meshes.insert(name.to_string(), Arc::new(mesh));
+                    }
+                }
+            }
+        }
+
+        let mut shapes_list = ShapeList::new();
+        if let Some(item) = parser.item(root, "shapes") {
+            let shapes = item
+                .as_array_of_tables()
+                .ok_or_else(|| parser.error(&item.span(), "'shapes' must be written as [[shapes]]"))?;
+            for table in shapes.iter() {
+                let span = table.span();
+                shapes_list.push(parser.node(table, &span, |node| parser.shape(node))?);
+            }
+        }
+        if let Some(item) = parser.item(root, "lights") {
+            let lights = item
+                .as_array_of_tables()
+                .ok_or_else(|| parser.error(&item.span(), "'lights' must be written as [[lights]]"))?;
+            for table in lights.iter() {
+                let span = table.span();
+                punctual.push(parser.node(table, &span, |node| parser.light(node))?);
+            }
+        }
+        parser.unknown_keys(root.table, &root_read.borrow())?;
+        if shapes_list.objects.is_empty() {
+            return Err(parser.error(&None, "scene has no shapes"));
+        }
+        //発光する形状は光源としてもサンプリングする
+        let light = shapes_list.lights(sampling);
+        //インスタンスも含めて上位のBVHを作る
+        let mut world = ShapeList::new();
+        world.push(Box::new(bvh.build(shapes_list.objects)));
+        Ok(Self { cam, aspect, world, light, punctual, background, environment })
+    }
+}
+
+impl Scene for FileScene {
+    fn camera(&self) -> &Camera {
+        &self.cam
+    }
+    fn world(&self) -> &dyn Shape {
+        &self.world
+    }
+    fn light(&self) -> Option<&Arc<dyn Shape>> {
+        self.light.as_ref()
+    }
+    fn background(&self) -> Color {
+        self.background
+    }
+    fn environment(&self) -> Option<&EnvMap> {
+        self.environment.as_ref()
+    }
+    fn punctual_lights(&self) -> &[Box<dyn Light>] {
+        &self.punctual
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "[camera]\nlookfrom = [0, 0, 5]\nlookat = [0, 0, 0]\nvfov = 40\n";
+    const WHITE: &str = "[materials.white]\ntype = \"lambertian\"\ncolor = [0.7, 0.7, 0.7]\n";
+    const SPHERE: &str = "[[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"white\"\n";
+
+    fn parse(src: &str) -> Result<FileScene, SceneError> {
+        FileScene::parse(Path::new("test.toml"), src, &BVHBuilder::new(BVHSplit::Sah, false), LightSampling::Bvh)
+    }
+
+    fn parse_err(src: &str) -> SceneError {
+        match parse(src) {
+            Ok(_) => panic!("scene should not parse:\n{}", src),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn minimal_scene_parses() {
+        let scene = parse(&[CAMERA, WHITE, SPHERE].concat()).unwrap();
+        assert_eq!(scene.world.objects.len(), 1);
+        assert!(scene.light.is_none());
+    }
+
+    #[test]
+    fn syntax_error_reports_line_and_column() {
+        let e = parse_err(&[CAMERA, "background = [1, 1\n", WHITE, SPHERE].concat());
+        assert_eq!((e.line, e.column), (6, 1));
+        assert!(e.to_string().starts_with("test.toml:6:1: "), "{}", e);
+    }
+
+    #[test]
+    fn missing_key_points_at_table() {
+        let e = parse_err(&[CAMERA, WHITE, "[[shapes]]\ntype = \"sphere\"\nmaterial = \"white\"\n"].concat());
+        assert_eq!(e.message, "missing key 'center'");
+        assert_eq!((e.line, e.column), (8, 1));
+    }
+
+    #[test]
+    fn wrong_value_type_points_at_value() {
+        let e = parse_err(&[CAMERA, WHITE, &SPHERE.replace("radius = 1", "radius = \"big\"")].concat());
+        assert_eq!(e.message, "expected a number, found string");
+        assert_eq!((e.line, e.column), (11, 10));
+    }
+
+    #[test]
+    fn unknown_key_points_at_key() {
+        let e = parse_err(&[CAMERA, WHITE, &SPHERE.replace("radius", "radus")].concat());
+        assert_eq!(e.message, "missing key 'radius'");
+        let e = parse_err(&[CAMERA, WHITE, SPHERE, "colour = [1, 0, 0]\n"].concat());
+        assert_eq!(e.message, "unknown key 'colour'");
+        assert_eq!((e.line, e.column), (13, 1));
+    }
+
+    #[test]
+    fn unknown_key_in_camera_and_root() {
+        let e = parse_err(&[CAMERA, "fov = 30\n", WHITE, SPHERE].concat());
+        assert_eq!(e.message, "unknown key 'fov'");
+        assert_eq!((e.line, e.column), (5, 1));
+        let e = parse_err(&["backgroud = [1, 1, 1]\n", CAMERA, WHITE, SPHERE].concat());
+        assert_eq!(e.message, "unknown key 'backgroud'");
+        assert_eq!((e.line, e.column), (1, 1));
+    }
+
+    #[test]
+    fn unknown_key_in_inline_table() {
+        let shape = [SPHERE, "transform = [{ type = \"translate\", offset = [1, 0, 0], scale = 2 }]\n"].concat();
+        let e = parse_err(&[CAMERA, WHITE, &shape].concat());
+        assert_eq!(e.message, "unknown key 'scale'");
+        assert_eq!((e.line, e.column), (13, 56));
+    }
+
+    #[test]
+    fn key_for_another_variant_is_unknown() {
+        let metal = "[materials.gold]\ntype = \"conductor\"\nmetal = \"gold\"\neta = [1, 1, 1]\n";
+        let e = parse_err(&[CAMERA, metal, SPHERE.replace("white", "gold").as_str()].concat());
+        assert_eq!(e.message, "unknown key 'eta'");
+        assert_eq!((e.line, e.column), (8, 1));
+    }
+
+    #[test]
+    fn unknown_type_points_at_value() {
+        let e = parse_err(&[CAMERA, &WHITE.replace("lambertian", "lambert"), SPHERE].concat());
+        assert_eq!(e.message, "unknown material type 'lambert'");
+        assert_eq!((e.line, e.column), (6, 8));
+        let e = parse_err(&[CAMERA, WHITE, &SPHERE.replace("sphere", "ball")].concat());
+        assert_eq!(e.message, "unknown shape type 'ball'");
+        assert_eq!((e.line, e.column), (9, 8));
+    }
+
+    #[test]
+    fn undefined_reference_points_at_name() {
+        let e = parse_err(&[CAMERA, WHITE, &SPHERE.replace("\"white\"", "\"black\"")].concat());
+        assert_eq!(e.message, "undefined material 'black'");
+        assert_eq!((e.line, e.column), (12, 12));
+    }
+
+    #[test]
+    fn errors_without_position() {
+        let e = parse_err(&[CAMERA, WHITE].concat());
+        assert_eq!((e.line, e.column), (0, 0));
+        assert_eq!(e.to_string(), "test.toml: scene has no shapes");
+        let bvh = BVHBuilder::new(BVHSplit::Sah, false);
+        let e = FileScene::load("no/such/scene.toml", &bvh, LightSampling::Bvh).err().expect("missing file should fail");
+        assert_eq!(e.line, 0);
+    }
+
+    #[test]
+    fn bundled_scenes_load() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let bvh = BVHBuilder::new(BVHSplit::Sah, false);
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "toml")
+                && let Err(e) = FileScene::load(&path.to_string_lossy(), &bvh, LightSampling::Bvh)
+            {
+                panic!("{}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::raymod::*;
+
+// 空の輝度(kcd/m²)をレンダラの単位に直す係数
+const SKY_SCALE: f64 = 1.0 / 40.0;
+// 大気外の太陽の照度(約128klux)を同じ単位にしたもの
+const SUN_IRRADIANCE: f64 = 128.0 * SKY_SCALE;
+/// Angular diameter of the sun in degrees
+pub const SUN_ANGLE: f64 = 0.53;
+
+// Perez関数の5係数
+#[derive(Copy, Clone, Debug)]
+struct Perez {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+}
+
+impl Perez {
+    fn value(&self, cos_theta: f64, gamma: f64) -> f64 {
+        let cos_gamma = gamma.cos();
+        (1.0 + self.a * (self.b / cos_theta.max(1e-4)).exp())
+            * (1.0 + self.c * (self.d * gamma).exp() + self.e * cos_gamma * cos_gamma)
+    }
+}
+
+// Preethamの昼光モデル(A Practical Analytic Model for Daylight, 1999)
+// 天頂の輝度と色度にPerez関数の比を掛けて空の各方向のYxyを求める
+pub struct PreethamSky {
+    pub sun_dir: Vec3,
+    pub turbidity: f64,
+    zenith: Vec3,
+    perez: [Perez; 3],
+}
+
+impl PreethamSky {
+    /// `elevation` and `azimuth` in degrees; azimuth 0 is +Z and 90 is +X
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64) -> Self {
+        let (el, az) = (elevation.clamp(0.0, 90.0).to_radians(), azimuth.to_radians());
+        let sun_dir = Vec3::new(el.cos() * az.sin(), el.sin(), el.cos() * az.cos());
+        let t = turbidity;
+        let theta_s = PI / 2.0 - el;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let cubic = |k: [f64; 4]| k[0] * theta_s.powi(3) + k[1] * theta_s.powi(2) + k[2] * theta_s + k[3];
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0])
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394])
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_yy = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0])
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516])
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let perez = [
+            Perez { a: 0.1787 * t - 1.4630, b: -0.3554 * t + 0.4275, c: -0.0227 * t + 5.3251, d: 0.1206 * t - 2.5771, e: -0.0670 * t + 0.3703 },
+            Perez { a: -0.0193 * t - 0.2592, b: -0.0665 * t + 0.0008, c: -0.0004 * t + 0.2125, d: -0.0641 * t - 0.8989, e: -0.0033 * t + 0.0452 },
+            Perez { a: -0.0167 * t - 0.2608, b: -0.0950 * t + 0.0092, c: -0.0079 * t + 0.2102, d: -0.0441 * t - 1.6537, e: -0.0109 * t + 0.0529 },
+        ];
+        //天頂での比で割っておく
+        let mut zenith = Vec3::new(zenith_y.max(0.0), zenith_x, zenith_yy);
+        zenith.x /= perez[0].value(1.0, theta_s);
+        zenith.y /= perez[1].value(1.0, theta_s);
+        zenith.z /= perez[2].value(1.0, theta_s);
+        Self { sun_dir, turbidity, zenith, perez }
+    }
+
+    /// Sky radiance (without the sun disc) toward direction `d` in the upper hemisphere
+    pub fn radiance(&self, d: Vec3) -> Color {
+        let d = d.norm();
+        let cos_theta = d.y.max(0.0);
+        let gamma = d.dot(&self.sun_dir).clamp(-1.0, 1.0).acos();
+        let lum = self.zenith.x * self.perez[0].value(cos_theta, gamma);
+        let x = self.zenith.y * self.perez[1].value(cos_theta, gamma);
+        let y = self.zenith.z * self.perez[2].value(cos_theta, gamma);
+        xyy_to_rgb(x, y, lum) * SKY_SCALE
+    }
+
+    /// Irradiance of the sun on a surface facing it, reddened by the atmosphere
+    pub fn sun_irradiance(&self) -> Color {
+        let theta = PI / 2.0 - self.sun_dir.y.clamp(0.0, 1.0).asin();
+        //相対エアマス(Kasten)
+        let m = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+        //Rayleigh散乱とエアロゾル(Ångström)による透過率を3波長で求める
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |lambda: f64| (-m * (0.008735 * lambda.powf(-4.08) + beta * lambda.powf(-1.3))).exp();
+        Color::new(transmittance(0.680), transmittance(0.550), transmittance(0.440)) * SUN_IRRADIANCE
+    }
+
+    /// Bake the sky into an importance-sampled environment map.
+    /// Below the horizon is a diffuse ground of albedo `ground` lit by the sun and sky.
+    pub fn bake(&self, width: usize, height: usize, intensity: f64, ground: Color) -> EnvMap {
+        let mut pixels = vec![Color::zero(); width * height];
+        let mut sky_irradiance = Color::zero();
+        for y in 0..height {
+            let v = (y as f64 + 0.5) / height as f64;
+            let solid_angle = 2.0 * PI * PI * (v * PI).sin() / (width * height) as f64;
+            for x in 0..width {
+                let d = equirect_direction((x as f64 + 0.5) / width as f64, v);
+                if d.y > 0.0 {
+                    let c = self.radiance(d);
+                    sky_irradiance = sky_irradiance + c * d.y * solid_angle;
+                    pixels[y * width + x] = c;
+                }
+            }
+        }
+        let ground_radiance = ground.mult(sky_irradiance + self.sun_irradiance() * self.sun_dir.y.max(0.0)) * FRAC_1_PI;
+        for (i, c) in pixels.iter_mut().enumerate() {
+            let v = ((i / width) as f64 + 0.5) / height as f64;
+            if v >= 0.5 {
+                *c = ground_radiance;
+            }
+        }
+        EnvMap::new(pixels, width, height, intensity, 0.0)
+    }
+
+    /// Sun disc as a directional light of the matching colour
+    pub fn sun(&self, intensity: f64) -> DirectionalLight {
+        DirectionalLight::new(-self.sun_dir, self.sun_irradiance() * intensity, SUN_ANGLE)
+    }
+}
+
+// CIE Yxy -> XYZ -> 線形sRGB
+fn xyy_to_rgb(x: f64, y: f64, lum: f64) -> Color {
+    if y <= 0.0 {
+        return Color::zero();
+    }
+    let cx = x / y * lum;
+    let cz = (1.0 - x - y) / y * lum;
+    Color::new(
+        (3.2406 * cx - 1.5372 * lum - 0.4986 * cz).max(0.0),
+        (-0.9689 * cx + 1.8758 * lum + 0.0415 * cz).max(0.0),
+        (0.0557 * cx - 0.2040 * lum + 1.0570 * cz).max(0.0),
+    )
+}
@@ -0,0 +1,140 @@
+use crate::raymod::*;
+use std::sync::LazyLock;
+
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+const LAMBDA_RANGE: f64 = LAMBDA_MAX - LAMBDA_MIN;
+
+// 左右で幅の違うガウス関数
+fn gaussian(x: f64, mu: f64, sigma_l: f64, sigma_r: f64) -> f64 {
+    let t = (x - mu) / if x < mu { sigma_l } else { sigma_r };
+    (-0.5 * t * t).exp()
+}
+
+// CIE 1931 等色関数の多峰ガウス近似(Wyman, Sloan, Shirley 2013)
+fn cie_xyz(lambda: f64) -> Vec3 {
+    Vec3::new(
+        1.056 * gaussian(lambda, 599.8, 37.9, 31.0) + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2),
+        0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1),
+        1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8),
+    )
+}
+
+// XYZ -> 線形sRGB。負の値も推定値の一部なので切らない
+fn xyz_to_rgb(c: Vec3) -> Color {
+    Color::new(
+        3.2406 * c.x - 1.5372 * c.y - 0.4986 * c.z,
+        -0.9689 * c.x + 1.8758 * c.y + 0.0415 * c.z,
+        0.0557 * c.x - 0.2040 * c.y + 1.0570 * c.z,
+    )
+}
+
+// 赤・緑・青の帯に分ける滑らかな1の分割(足すと常に1)
+fn bands(lambda: f64) -> Vec3 {
+    let sigmoid = |x: f64| 1.0 / (1.0 + (-x).exp());
+    let r = sigmoid((lambda - 585.0) / 5.0);
+    let b = 1.0 - sigmoid((lambda - 485.0) / 5.0);
+    Vec3::new(r, 1.0 - r - b, b)
+}
+
+// 波長ごとの変換係数を作るための表
+struct Tables {
+    //平らなスペクトルが白(1,1,1)になるように割る値
+    white: Color,
+    //帯を射影したRGBの逆行列。これを掛けるとRGB->スペクトル->RGBが元に戻る
+    unmix: Matrix4,
+}
+
+static TABLES: LazyLock<Tables> = LazyLock::new(|| {
+    const STEPS: usize = 4000;
+    let dl = LAMBDA_RANGE / STEPS as f64;
+    let lambdas = (0..STEPS).map(|i| LAMBDA_MIN + (i as f64 + 0.5) * dl);
+    let white = lambdas.clone().fold(Color::zero(), |c, l| c + xyz_to_rgb(cie_xyz(l)) * dl);
+    //mix[j][k]: 帯kだけのスペクトルをRGBに戻した時のj成分
+    let mut mix = Matrix4::new([[0.0; 4], [0.0; 4], [0.0; 4], [0.0, 0.0, 0.0, 1.0]]);
+    for l in lambdas {
+        let w = xyz_to_rgb(cie_xyz(l));
+        let g = bands(l);
+        for j in 0..3 {
+            for k in 0..3 {
+                mix.m[j][k] += w[j] / white[j] * g[k] * dl;
+            }
+        }
+    }
+    Tables { white, unmix: mix.inverse().expect("spectral basis is singular") }
+});
+
+/// Wavelengths carried by one path. The three channels of a `Color` hold the
+/// spectrum at these wavelengths instead of red, green and blue; in RGB mode
+/// the channels are left as they are.
+#[derive(Clone, Copy, Debug)]
+pub struct Wavelengths {
+    lambda: [f64; 3],
+    //RGBからその波長での値を求める係数
+    basis: [Color; 3],
+    //その波長での値からRGBへの寄与(1/pdfと波長数の平均込み)
+    weight: [Color; 3],
+    //分散で副波長を打ち切ったか
+    single: bool,
+}
+
+impl Wavelengths {
+    /// Plain RGB rendering: every conversion is the identity
+    pub fn rgb() -> Self {
+        let axes = [Vec3::xaxis(), Vec3::yaxis(), Vec3::zaxis()];
+        Self { lambda: [0.0; 3], basis: axes, weight: axes, single: false }
+    }
+
+    /// Hero wavelength sampling: one uniform wavelength and two more spaced
+    /// evenly around the visible range
+    pub fn sample() -> Self {
+        let tables = &*TABLES;
+        let hero = random() * LAMBDA_RANGE;
+        let lambda: [f64; 3] = std::array::from_fn(|i| LAMBDA_MIN + (hero + i as f64 * LAMBDA_RANGE / 3.0) % LAMBDA_RANGE);
+        let basis = lambda.map(|l| {
+            let g = bands(l);
+            let u = &tables.unmix.m;
+            Color::new(
+                g.x * u[0][0] + g.y * u[1][0] + g.z * u[2][0],
+                g.x * u[0][1] + g.y * u[1][1] + g.z * u[2][1],
+                g.x * u[0][2] + g.y * u[1][2] + g.z * u[2][2],
+            )
+        });
+        let weight = lambda.map(|l| {
+            let w = xyz_to_rgb(cie_xyz(l));
+            let white = tables.white;
+            Color::new(w.x / white.x, w.y / white.y, w.z / white.z) * (LAMBDA_RANGE / 3.0)
+        });
+        Self { lambda, basis, weight, single: false }
+    }
+
+    pub fn is_spectral(&self) -> bool {
+        self.lambda[0] > 0.0
+    }
+
+    /// Wavelength in nm that dispersive materials should use, 0 in RGB mode
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    /// Value of an RGB reflectance or radiance at each carried wavelength
+    pub fn upsample(&self, c: Color) -> Color {
+        Color::new(self.basis[0].dot(&c), self.basis[1].dot(&c), self.basis[2].dot(&c))
+    }
+
+    /// Convert the radiance at the carried wavelengths back to linear sRGB
+    pub fn to_rgb(self, l: Color) -> Color {
+        self.weight[0] * l.x + self.weight[1] * l.y + self.weight[2] * l.z
+    }
+
+    /// Keep only the hero wavelength once the path has split by wavelength.
+    /// The hero takes over the weight of the dropped wavelengths.
+    pub fn terminate_secondary(&mut self, throughput: Color) -> Color {
+        if !self.is_spectral() || self.single {
+            return throughput;
+        }
+        self.single = true;
+        Color::new(throughput.x * 3.0, 0.0, 0.0)
+    }
+}
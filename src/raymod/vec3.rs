@@ -4,7 +4,7 @@ use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
 use std::ops::{Index, IndexMut};
 pub use std::f64::consts::*;
 
-const PI2:f64 = PI*PI;
+const PI2:f64 = PI*2.0;
 
 pub fn random() -> f64 {
     rand::random::<f64>()
@@ -31,6 +31,9 @@ impl Vec3 {
     pub fn zero() -> Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
+    pub fn full(x: f64) -> Vec3 {
+        Vec3::new(x, x, x)
+    }
     pub fn xaxis() -> Vec3 {
         Vec3::new(1.0, 0.0, 0.0)
     }
@@ -46,26 +49,26 @@ impl Vec3 {
     }
     pub fn norm(mut self) -> Vec3 {
         let l = 1.0 / (self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
-        self.x = self.x * l;
-        self.y = self.y * l;
-        self.z = self.z * l;
+        self.x *= l;
+        self.y *= l;
+        self.z *= l;
         self
     }
     pub fn dot(&self, b: &Vec3) -> f64 {
-        return self.x * b.x + self.y * b.y + self.z * b.z;
+        self.x * b.x + self.y * b.y + self.z * b.z
     }
     pub fn length(&self) -> f64 {
-        return self.x * self.x + self.y * self.y + self.z * self.z;
+        self.x * self.x + self.y * self.y + self.z * self.z
     }
     pub fn random() -> Vec3 {
-        return Vec3::new(random(), random(), random());
+        Vec3::new(random(), random(), random())
     }
     pub fn random_full() -> Vec3 {
         let x = random();
-        return Vec3::new(x, x, x);
+        Vec3::new(x, x, x)
     }
     pub fn vec3_random_range(a: f64, b: f64) -> Vec3 {
-        return Vec3::new(random_range(a, b), random_range(a, b), random_range(a, b));
+        Vec3::new(random_range(a, b), random_range(a, b), random_range(a, b))
     }
     pub fn random_hemisphere() -> Vec3 {
         loop {
@@ -90,6 +93,13 @@ impl Vec3 {
         let r2sqrt = r2.sqrt();
         Self::new(x * r2sqrt, y * r2sqrt, z)
     }
+    /// Uniformly distributed direction on the unit sphere
+    pub fn random_unit_vector() -> Self {
+        let z = 1.0 - 2.0 * random();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let (x, y) = (2.0 * PI * random()).sin_cos();
+        Self::new(x * r, y * r, z)
+    }
     pub fn random_to_sphere(radius: f64, distance_squared: f64) -> Self {
         let rx=random();let ry=random();
         let rr = radius.powi(2).min(distance_squared);
@@ -108,7 +118,7 @@ impl Vec3 {
         let dt = uv.dot(&normal);
         let d = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
         if d > 0.0 {
-            Some((uv - normal * dt) * (-1.0 * ni_over_nt) - normal * d.sqrt())
+            Some((uv - normal * dt) * -ni_over_nt - normal * d.sqrt())
         } else {
             None
         }
@@ -206,13 +216,14 @@ impl IndexMut<usize> for Vec3 {
 pub struct ONB {
     axis: [Vec3; 3],
 }
+#[allow(dead_code)]
 impl ONB {
     pub fn new(n: Vec3) -> Self {
         let w = n.norm();
         let v = if w.x.abs() > 0.9 {
-            w % (Vec3::yaxis()).norm()
+            (w % Vec3::yaxis()).norm()
         } else {
-            w % (Vec3::xaxis()).norm()
+            (w % Vec3::xaxis()).norm()
         };
         let u = w % v;
         Self { axis: [u, v, w] }
@@ -223,19 +234,17 @@ impl ONB {
     pub fn local(&self, v: Vec3) -> Vec3 {
         self.axis[0] * v.x + self.axis[1] * v.y + self.axis[2] * v.z
     }
+    /// Inverse of `local`: components of a world vector along u, v, w
+    pub fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(v.dot(&self.axis[0]), v.dot(&self.axis[1]), v.dot(&self.axis[2]))
+    }
 }
 
 
 
 
 fn clamp(x: f64) -> f64 {
-    if x < 0.0 {
-        0.0
-    } else if x > 1.0 {
-        1.0
-    } else {
-        x
-    }
+    x.clamp(0.0, 1.0)
 }
 
 fn to_int(x: f64) -> u8 {
@@ -246,13 +255,13 @@ fn to_int(x: f64) -> u8 {
 fn save_ppm_file(filename: &str, image: Vec<Color>, width: usize, height: usize) {
     let mut f = fs::File::create(filename).unwrap();
     writeln!(f, "P3\n{} {}\n{}", width, height, 255).unwrap();
-    for i in 0..(width * (height)) {
+    for c in image.iter().take(width * height) {
         write!(
             f,
             "{} {} {} ",
-            to_int(image[i as usize].x),
-            to_int(image[i as usize].y),
-            to_int(image[i as usize].z)
+            to_int(c.x),
+            to_int(c.y),
+            to_int(c.z)
         )
         .unwrap();
     }